@@ -1,3 +1,6 @@
 fn main() {
-    println!("cargo:rustc-link-search=h3-3.7.2/build/lib");
+    // 只有通过FFI使用h3时才需要链接预先编译好的C库
+    if std::env::var_os("CARGO_FEATURE_H3_FFI").is_some() {
+        println!("cargo:rustc-link-search=h3-3.7.2/build/lib");
+    }
 }
@@ -3,6 +3,12 @@
 mongo <<EOF
 use with-baby-geo;
 db.locations.createIndex({location: "2dsphere"});
-db.locations.createIndex({uid: 1});
+db.locations.createIndex({uid: 1, _id: -1});
+db.locations.createIndex({category: 1});
+db.locations.createIndex({tags: 1});
+db.locations.createIndex({geo_indices: 1});
+// transaction模式下事务中用到的格子标记文档和lock模式下的fencing token, 事务内不能隐式创建集合(4.4以下)
+db.createCollection("cells");
+db.createCollection("fences");
 EOF
 
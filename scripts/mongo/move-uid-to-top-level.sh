@@ -0,0 +1,7 @@
+#!/bin/sh
+
+# 早期版本将uid写在了location子文档中, 此脚本将其移动到顶层
+mongo <<EOF
+use with-baby-geo;
+db.locations.updateMany({"location.uid": {\$exists: true}}, [{\$set: {uid: "\$location.uid"}}, {\$unset: "location.uid"}]);
+EOF
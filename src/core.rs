@@ -1,81 +1,900 @@
-use crate::models::{Location, LocationCommand};
-use anyhow::Error;
+use crate::error::Error;
+use crate::geo::Rect;
+use crate::models::{Fence, Location, LocationAttributes, LocationCommand, LocationFilter, LocationUpdate, LocationWithDistance};
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::RangeInclusive;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::time::Instant;
 
 pub(crate) trait Key<'a>: Serialize + Deserialize<'a> + Display + Send + Sync + Ord + Clone {}
 
 impl<'a> Key<'a> for String {}
 impl<'a> Key<'a> for u64 {}
 
+// 每次加锁都会得到一个单调递增的fencing token
+pub(crate) trait Fenced {
+    fn token(&self) -> u64;
+
+    // 锁的过期时刻, 获取或延长锁时更新, None表示不会过期
+    fn expires_at(&self) -> Option<Instant> {
+        None
+    }
+}
+
 pub(crate) trait Mutex<K, L>
 where
     K: 'static,
+    L: Fenced,
+{
+    fn multiple_acquire(self, keys: Vec<K>) -> Pin<Box<dyn Future<Output = Result<Vec<L>, Error>> + Send>>;
+    fn multiple_release(self, locks: Vec<L>) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+    #[allow(dead_code)]
+    fn single_acquire(self, key: K) -> Pin<Box<dyn Future<Output = Result<L, Error>> + Send>>;
+    #[allow(dead_code)]
+    fn single_release(self, lock: L) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+    // 延长已持有的锁的有效时长, 锁已经过期或被别人获取时返回LockExpired
+    fn extend<'a>(self, locks: &'a mut [L]) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>
+    where
+        L: Send;
+
+    // 持有锁期间是否自动延长锁, 延长的时机由锁的过期时刻决定
+    fn watchdog(&self) -> bool {
+        false
+    }
+
+    // 获取所有key的锁并返回守卫, 守卫被丢弃时会自动释放锁
+    fn lock(self, keys: Vec<K>) -> Pin<Box<dyn Future<Output = Result<LockGuard<Self, K, L>, Error>> + Send>>
+    where
+        Self: Sized + Clone + Send + 'static,
+        K: Send,
+        L: Fenced + Send + 'static,
+    {
+        Box::pin(async move {
+            let locks = self.clone().multiple_acquire(keys).await?;
+            Ok(LockGuard {
+                mutex: self,
+                locks: Some(locks),
+                expired: Expiry::default(),
+                _key: PhantomData,
+            })
+        })
+    }
+}
+
+pub(crate) struct LockGuard<M, K, L>
+where
+    M: Mutex<K, L> + Clone + Send + 'static,
+    K: Send + 'static,
+    L: Fenced + Send + 'static,
+{
+    mutex: M,
+    locks: Option<Vec<L>>,
+    expired: Expiry,
+    _key: PhantomData<fn() -> K>,
+}
+
+// 后台延长锁失败或发现锁已过期时设置, 临界区在开始写入之前检查
+#[derive(Clone, Default)]
+pub(crate) struct Expiry(Arc<AtomicBool>);
+
+impl Expiry {
+    fn expire(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if self.0.load(Ordering::SeqCst) {
+            return Err(Error::LockExpired);
+        }
+        Ok(())
+    }
+}
+
+impl<M, K, L> LockGuard<M, K, L>
+where
+    M: Mutex<K, L> + Clone + Send + 'static,
+    K: Send + 'static,
+    L: Fenced + Send + 'static,
 {
-    fn multiple_acquire(self, keys: Vec<K>) -> Pin<Box<dyn Future<Output = Result<Vec<L>, Error>>>>;
-    fn multiple_release(self, locks: Vec<L>) -> Pin<Box<dyn Future<Output = Result<(), Error>>>>;
-    fn single_acquire(self, key: K) -> Pin<Box<dyn Future<Output = Result<L, Error>>>>;
-    fn single_release(self, lock: L) -> Pin<Box<dyn Future<Output = Result<(), Error>>>>;
+    pub(crate) fn token(&self) -> u64 {
+        self.locks.iter().flatten().map(Fenced::token).max().unwrap_or_default()
+    }
+
+    pub(crate) fn expiry(&self) -> Expiry {
+        self.expired.clone()
+    }
+
+    // 执行临界区, 同时在后台延长锁: 每次在剩余有效时长的三分之一处延长.
+    // 延长失败或锁已过期时停止延长并设置expiry, 不中断临界区: 尚未开始的写入由临界区检查expiry后放弃,
+    // 已经开始的写入由存储层按fencing token拒绝
+    pub(crate) async fn watch<T, F>(&mut self, critical: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let locks = match self.locks.as_deref_mut() {
+            Some(locks) if self.mutex.watchdog() => locks,
+            _ => return critical.await,
+        };
+        let (mutex, expired) = (self.mutex.clone(), self.expired.clone());
+        let refresh = async move {
+            while let Some(expires_at) = locks.iter().filter_map(Fenced::expires_at).min() {
+                let now = Instant::now();
+                if expires_at <= now {
+                    warn!("locks expired before they could be extended");
+                    expired.expire();
+                    return;
+                }
+                tokio::time::sleep((expires_at - now) / 3).await;
+                if let Err(e) = mutex.clone().extend(locks).await {
+                    warn!("failed to extend locks: {e}");
+                    expired.expire();
+                    return;
+                }
+            }
+        };
+        tokio::pin!(critical);
+        tokio::select! {
+            res = &mut critical => return res,
+            _ = refresh => {}
+        }
+        critical.await
+    }
+
+    // 临界区正常结束时显式释放, 可以拿到释放的结果
+    pub(crate) async fn release(mut self) -> Result<(), Error> {
+        match self.locks.take() {
+            Some(locks) => self.mutex.clone().multiple_release(locks).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl<M, K, L> Drop for LockGuard<M, K, L>
+where
+    M: Mutex<K, L> + Clone + Send + 'static,
+    K: Send + 'static,
+    L: Fenced + Send + 'static,
+{
+    // 未显式释放(比如中途出错返回)时在后台异步释放
+    fn drop(&mut self) {
+        if let Some(locks) = self.locks.take() {
+            let release = self.mutex.clone().multiple_release(locks);
+            match tokio::runtime::Handle::try_current() {
+                Ok(handle) => {
+                    handle.spawn(async move {
+                        if let Err(e) = release.await {
+                            error!("failed to release locks: {e}");
+                        }
+                    });
+                }
+                Err(e) => error!("failed to release locks, they will be released after expiration: {e}"),
+            }
+        }
+    }
+}
+
+// 临界区已经结束, 写入可能已经提交, 释放失败时只记录日志, 锁会在过期后自动释放
+async fn release<M, K, L>(guard: LockGuard<M, K, L>)
+where
+    M: Mutex<K, L> + Clone + Send + 'static,
+    K: Send + 'static,
+    L: Fenced + Send + 'static,
+{
+    if let Err(e) = guard.release().await {
+        error!("failed to release locks, they will be released after expiration: {e}");
+    }
 }
 
 pub(crate) trait Indexer<'a, I>
 where
     I: std::fmt::Display + Send + Sync + 'a,
 {
-    fn index(&self, latitude: f64, longitude: f64) -> I;
-    fn neighbors(&self, index: I, distance: f64) -> Vec<I>;
+    // 地点在每一层所在的格子, 由粗到细, 不同层的格子索引互不相同
+    fn indices(&self, latitude: f64, longitude: f64) -> Vec<I>;
+    // 距离为distance(米)的操作使用的层, 在覆盖圆的格子数不超过上限的前提下尽量细
+    fn level(&self, distance: f64) -> usize;
+    // 第level层中与以(latitude, longitude)为圆心、distance(米)为半径的圆相交的所有格子
+    fn neighbors(&self, latitude: f64, longitude: f64, distance: f64, level: usize) -> Vec<I>;
+    // 第level层中与经纬度矩形相交的所有格子, 可以多出少量不相交的格子
+    fn polyfill(&self, rect: &Rect, level: usize) -> Vec<I>;
+    // 把同一个父格子的所有子格子合并为父格子, 直到不能再合并, cells必须属于同一层且互不重复
+    fn compact(&self, cells: Vec<I>) -> Vec<I>;
+    // cell(可以比第level层粗)在第level层的所有后代都落在返回的区间内, 区间内的其他层的索引可能被误匹配, 由距离条件排除
+    fn range(&self, cell: &I, level: usize) -> RangeInclusive<I>;
 }
 
 pub(crate) trait Persister<I> {
-    fn insert<'a>(&'a self, loc: LocationCommand<I>) -> Pin<Box<dyn Future<Output = Result<String, Error>> + 'a>>
+    // 记录加锁得到的fencing token, 任一格子已记录更大的token时整体拒绝并返回LockExpired.
+    // 必须在重复检查之前调用, 这样锁已过期的写入者之后的insert/update一定会被拒绝
+    fn fence<'a>(&'a self, fence: Fence<I>) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>>
+    where
+        I: 'a;
+    // insert和update在同一个原子操作中再次检查fence并写入
+    fn insert<'a>(&'a self, loc: LocationCommand<I>, fence: Fence<I>) -> Pin<Box<dyn Future<Output = Result<String, Error>> + 'a>>
+    where
+        I: 'a;
+    fn update<'a>(&'a self, id: String, loc: LocationUpdate<I>, fence: Fence<I>) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>>
+    where
+        I: 'a;
+    fn get<'a>(&'a self, id: String) -> Pin<Box<dyn Future<Output = Result<Option<Location<I>>, Error>> + 'a>>
+    where
+        I: 'a;
+    fn delete<'a>(&'a self, id: String) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + 'a>>
     where
         I: 'a;
-    fn query<'a>(&'a self, indices: Vec<I>, latitude: f64, longitude: f64, distance: f64, page: i64, size: i64) -> Pin<Box<dyn Future<Output = Result<(Vec<Location<I>>, u64), Error>> + 'a>>
+    fn list_by_owner<'a>(&'a self, uid: String, page: i64, size: i64) -> Pin<Box<dyn Future<Output = Result<(Vec<Location<I>>, u64), Error>> + 'a>>
     where
         I: 'a;
-    fn exists<'a>(&'a self, indices: Vec<I>, latitude: f64, longitude: f64, distance: f64) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + 'a>>
+    // 按geo_indices落在任一区间内过滤
+    fn query<'a>(
+        &'a self,
+        ranges: Vec<RangeInclusive<I>>,
+        latitude: f64,
+        longitude: f64,
+        distance: f64,
+        filter: LocationFilter,
+        page: i64,
+        size: i64,
+    ) -> Pin<Box<dyn Future<Output = Result<(Vec<LocationWithDistance<I>>, u64), Error>> + 'a>>
+    where
+        I: 'a;
+    // 按geo_indices落在任一区间内过滤, 返回位于矩形内的地点, 按创建时间由新到旧排序
+    fn query_within<'a>(
+        &'a self,
+        ranges: Vec<RangeInclusive<I>>,
+        rect: Rect,
+        filter: LocationFilter,
+        page: i64,
+        size: i64,
+    ) -> Pin<Box<dyn Future<Output = Result<(Vec<Location<I>>, u64), Error>> + 'a>>
+    where
+        I: 'a;
+    fn exists<'a>(&'a self, indices: Vec<I>, latitude: f64, longitude: f64, distance: f64, exclude: Option<String>) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + 'a>>
+    where
+        I: 'a;
+}
+
+// 由存储层在同一个事务中完成重复检查和写入, 不需要分布式锁, 附近已存在地点时返回Conflict
+pub(crate) trait UniquePersister<I>: Persister<I> {
+    fn insert_unique<'a>(&'a self, cells: Vec<I>, distance: f64, loc: LocationCommand<I>) -> Pin<Box<dyn Future<Output = Result<String, Error>> + 'a>>
+    where
+        I: 'a;
+    fn update_unique<'a>(&'a self, id: String, cells: Vec<I>, distance: f64, loc: LocationUpdate<I>) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>>
     where
         I: 'a;
 }
 
-pub(crate) async fn add_location<'a, M, I, P, K, L>(mutex: M, indexer: I, persister: P, latitude: f64, longitude: f64, distance: f64, uid: String) -> Result<String, Error>
+// distance为重复检查的半径, level为加锁和冲突检测的格子所在的层.
+// 不同分类的重复半径可能不同, 所有写操作必须使用同一层, 否则彼此冲突的两次写入锁住的格子可能没有交集
+pub(crate) async fn add_location<'a, M, I, P, K, L>(
+    mutex: M,
+    indexer: I,
+    persister: P,
+    latitude: f64,
+    longitude: f64,
+    distance: f64,
+    level: usize,
+    uid: String,
+    attributes: LocationAttributes,
+) -> Result<String, Error>
 where
-    M: Mutex<K, L> + Clone + 'static,
+    M: Mutex<K, L> + Clone + Send + 'static,
     I: Indexer<'a, K>,
     P: Persister<K>,
     K: Key<'static> + 'static,
-    L: 'a,
+    L: Fenced + Send + 'static,
 {
-    let idx = indexer.index(latitude, longitude);
-    let mut neighbors = indexer.neighbors(idx.clone(), distance);
+    let geo_indices = indexer.indices(latitude, longitude);
+    let geo_index = geo_indices.last().unwrap().clone();
+    let mut neighbors = indexer.neighbors(latitude, longitude, distance, level);
     neighbors.sort();
-    let locks = mutex.clone().multiple_acquire(neighbors.clone()).await?;
-    if persister.exists(neighbors.clone(), latitude, longitude, distance).await? {
-        mutex.clone().multiple_release(locks).await?;
-        return Err(Error::msg("already exists location nearby"));
-    }
-    let res = persister
-        .insert(LocationCommand {
-            latitude: latitude,
-            longitude: longitude,
-            geo_index: idx,
-            uid: uid,
+    let mut guard = mutex.lock(neighbors.clone()).await?;
+    let fence = Fence {
+        token: guard.token(),
+        cells: neighbors.clone(),
+    };
+    let expiry = guard.expiry();
+    let res = guard
+        .watch(async {
+            persister.fence(fence.clone()).await?;
+            if persister.exists(neighbors, latitude, longitude, distance, None).await? {
+                return Err(Error::Conflict("already exists location nearby".into()));
+            }
+            // 重复检查期间锁已过期时放弃写入
+            expiry.check()?;
+            persister
+                .insert(
+                    LocationCommand {
+                        latitude,
+                        longitude,
+                        geo_index,
+                        geo_indices,
+                        uid,
+                        attributes,
+                    },
+                    fence,
+                )
+                .await
+        })
+        .await;
+    release(guard).await;
+    res
+}
+
+pub(crate) async fn update_location<'a, M, I, P, K, L>(mutex: M, indexer: I, persister: P, id: String, latitude: f64, longitude: f64, distance: f64, level: usize) -> Result<(), Error>
+where
+    M: Mutex<K, L> + Clone + Send + 'static,
+    I: Indexer<'a, K>,
+    P: Persister<K>,
+    K: Key<'static> + 'static,
+    L: Fenced + Send + 'static,
+{
+    let old = persister.get(id.clone()).await?.ok_or_else(|| Error::NotFound(format!("location not found: {id}")))?;
+    let geo_indices = indexer.indices(latitude, longitude);
+    let geo_index = geo_indices.last().unwrap().clone();
+    let neighbors = indexer.neighbors(latitude, longitude, distance, level);
+    // 旧位置与新位置周边的格子都需要加锁
+    let mut keys = indexer.neighbors(old.latitude, old.longitude, distance, level);
+    keys.extend(neighbors.clone());
+    keys.sort();
+    keys.dedup();
+    let mut guard = mutex.lock(keys.clone()).await?;
+    let fence = Fence { token: guard.token(), cells: keys };
+    let expiry = guard.expiry();
+    let res = guard
+        .watch(async {
+            persister.fence(fence.clone()).await?;
+            if persister.exists(neighbors, latitude, longitude, distance, Some(id.clone())).await? {
+                return Err(Error::Conflict("already exists location nearby".into()));
+            }
+            expiry.check()?;
+            persister
+                .update(
+                    id,
+                    LocationUpdate {
+                        latitude,
+                        longitude,
+                        geo_index,
+                        geo_indices,
+                    },
+                    fence,
+                )
+                .await
         })
-        .await?;
-    mutex.multiple_release(locks).await?;
-    Ok(res)
+        .await;
+    release(guard).await;
+    res
+}
+
+pub(crate) async fn add_location_unique<'a, I, P, K>(
+    indexer: I,
+    persister: P,
+    latitude: f64,
+    longitude: f64,
+    distance: f64,
+    level: usize,
+    uid: String,
+    attributes: LocationAttributes,
+) -> Result<String, Error>
+where
+    I: Indexer<'a, K>,
+    P: UniquePersister<K>,
+    K: Key<'static> + 'static,
+{
+    let geo_indices = indexer.indices(latitude, longitude);
+    let geo_index = geo_indices.last().unwrap().clone();
+    let mut neighbors = indexer.neighbors(latitude, longitude, distance, level);
+    neighbors.sort();
+    persister
+        .insert_unique(
+            neighbors,
+            distance,
+            LocationCommand {
+                latitude,
+                longitude,
+                geo_index,
+                geo_indices,
+                uid,
+                attributes,
+            },
+        )
+        .await
 }
 
-pub(crate) async fn nearby_locations<'a, I, P, K>(indexer: &I, persister: &P, latitude: f64, longitude: f64, distance: f64, page: i64, size: i64) -> Result<(Vec<Location<K>>, u64), Error>
+pub(crate) async fn update_location_unique<'a, I, P, K>(indexer: I, persister: P, id: String, latitude: f64, longitude: f64, distance: f64, level: usize) -> Result<(), Error>
+where
+    I: Indexer<'a, K>,
+    P: UniquePersister<K>,
+    K: Key<'static> + 'static,
+{
+    let old = persister.get(id.clone()).await?.ok_or_else(|| Error::NotFound(format!("location not found: {id}")))?;
+    let geo_indices = indexer.indices(latitude, longitude);
+    let geo_index = geo_indices.last().unwrap().clone();
+    // 与加锁模式一致, 旧位置与新位置周边的格子都要参与冲突检测
+    let mut cells = indexer.neighbors(old.latitude, old.longitude, distance, level);
+    cells.extend(indexer.neighbors(latitude, longitude, distance, level));
+    cells.sort();
+    cells.dedup();
+    persister
+        .update_unique(
+            id,
+            cells,
+            distance,
+            LocationUpdate {
+                latitude,
+                longitude,
+                geo_index,
+                geo_indices,
+            },
+        )
+        .await
+}
+
+pub(crate) async fn nearby_locations<'a, I, P, K>(
+    indexer: &I,
+    persister: &P,
+    latitude: f64,
+    longitude: f64,
+    distance: f64,
+    filter: LocationFilter,
+    page: i64,
+    size: i64,
+) -> Result<(Vec<LocationWithDistance<K>>, u64), Error>
 where
     I: Indexer<'a, K>,
     P: Persister<K>,
     K: Key<'a> + 'a,
 {
-    let idx = indexer.index(latitude, longitude);
-    let indices = indexer.neighbors(idx, distance);
-    let (locs, total) = persister.query(indices, latitude, longitude, distance, page, size).await?;
+    // 只用于查询, 加锁和重复检查仍使用未合并的格子, 保证同一距离的写操作锁同一组key
+    let level = indexer.level(distance);
+    let cells = indexer.compact(indexer.neighbors(latitude, longitude, distance, level));
+    let ranges = cells.iter().map(|cell| indexer.range(cell, level)).collect();
+    let (locs, total) = persister.query(ranges, latitude, longitude, distance, filter, page, size).await?;
     Ok((locs, total))
 }
+
+pub(crate) async fn locations_within<'a, I, P, K>(indexer: &I, persister: &P, rect: Rect, filter: LocationFilter, page: i64, size: i64) -> Result<(Vec<Location<K>>, u64), Error>
+where
+    I: Indexer<'a, K>,
+    P: Persister<K>,
+    K: Key<'a> + 'a,
+{
+    // 按矩形外接圆的半径选择层, 格子数同样不超过上限
+    let level = indexer.level(rect.radius());
+    let cells = indexer.compact(indexer.polyfill(&rect, level));
+    let ranges = cells.iter().map(|cell| indexer.range(cell, level)).collect();
+    persister.query_within(ranges, rect, filter, page, size).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::indexers::H3Indexer;
+    use crate::mutexes::LocalMutex;
+    use crate::persisters::InMemoryPersister;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    // 获取和每次延长后都有3秒的有效时长
+    const TEST_VALIDITY: Duration = Duration::from_secs(3);
+
+    struct TestLock {
+        expires_at: Instant,
+    }
+
+    impl TestLock {
+        fn new() -> Self {
+            Self {
+                expires_at: Instant::now() + TEST_VALIDITY,
+            }
+        }
+    }
+
+    impl Fenced for TestLock {
+        fn token(&self) -> u64 {
+            1
+        }
+
+        fn expires_at(&self) -> Option<Instant> {
+            Some(self.expires_at)
+        }
+    }
+
+    // 只记录延长次数, 延长第fail_at次时失败
+    #[derive(Clone)]
+    struct TestMutex {
+        extended: Arc<AtomicUsize>,
+        fail_at: usize,
+        // 释放锁时是否失败
+        release_fails: bool,
+    }
+
+    impl Mutex<i64, TestLock> for TestMutex {
+        fn multiple_acquire(self, keys: Vec<i64>) -> Pin<Box<dyn Future<Output = Result<Vec<TestLock>, Error>> + Send>> {
+            Box::pin(async move { Ok(keys.iter().map(|_| TestLock::new()).collect()) })
+        }
+
+        fn multiple_release(self, _: Vec<TestLock>) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> {
+            Box::pin(async move {
+                if self.release_fails {
+                    return Err(Error::BackendUnavailable("redis".into()));
+                }
+                Ok(())
+            })
+        }
+
+        fn single_acquire(self, _: i64) -> Pin<Box<dyn Future<Output = Result<TestLock, Error>> + Send>> {
+            Box::pin(async { Ok(TestLock::new()) })
+        }
+
+        fn single_release(self, _: TestLock) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn extend<'a>(self, locks: &'a mut [TestLock]) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>> {
+            Box::pin(async move {
+                if self.extended.fetch_add(1, Ordering::SeqCst) + 1 >= self.fail_at {
+                    return Err(Error::LockExpired);
+                }
+                for lock in locks {
+                    lock.expires_at = Instant::now() + TEST_VALIDITY;
+                }
+                Ok(())
+            })
+        }
+
+        fn watchdog(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lock_guard_watch() {
+        let mutex = TestMutex {
+            extended: Arc::new(AtomicUsize::new(0)),
+            fail_at: 5,
+            release_fails: false,
+        };
+        let mut guard = mutex.clone().lock(vec![1]).await.unwrap();
+        let res = guard
+            .watch(async {
+                tokio::time::sleep(Duration::from_millis(3500)).await;
+                Ok(1)
+            })
+            .await;
+        // 每次在剩余有效时长的三分之一处延长, 即每秒一次
+        assert_eq!(res.unwrap(), 1);
+        assert_eq!(mutex.extended.load(Ordering::SeqCst), 3);
+        assert!(guard.expiry().check().is_ok());
+        // 延长失败后不再延长, 但临界区照常执行完, 不会中断已经开始的写入
+        let res = guard
+            .watch(async {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                Ok(2)
+            })
+            .await;
+        assert_eq!(res.unwrap(), 2);
+        assert_eq!(mutex.extended.load(Ordering::SeqCst), 5);
+        assert!(matches!(guard.expiry().check(), Err(Error::LockExpired)));
+        // 锁已经过期时不再尝试延长
+        let res = guard.watch(async { Ok(3) }).await;
+        assert_eq!(res.unwrap(), 3);
+        assert_eq!(mutex.extended.load(Ordering::SeqCst), 5);
+        guard.release().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_release_failure_keeps_write() {
+        let mutex = TestMutex {
+            extended: Arc::new(AtomicUsize::new(0)),
+            fail_at: usize::MAX,
+            release_fails: true,
+        };
+        let indexer = H3Indexer::new(vec![6, 7, 8], 100).unwrap();
+        let level = indexer.level(500.0);
+        let persister = InMemoryPersister::<i64>::new();
+        // 写入已提交, 释放锁失败不影响返回结果
+        let id = add_location(
+            mutex.clone(),
+            indexer.clone(),
+            persister.clone(),
+            36.657004,
+            117.0242607,
+            500.0,
+            level,
+            "1".into(),
+            LocationAttributes::default(),
+        )
+        .await
+        .unwrap();
+        update_location(mutex, indexer, persister.clone(), id.clone(), 36.657504, 117.0242607, 500.0, level).await.unwrap();
+        assert_eq!(persister.get(id).await.unwrap().unwrap().latitude, 36.657504);
+    }
+
+    // 重复检查耗时delay, 其余操作直接交给内存存储
+    #[derive(Clone)]
+    struct SlowExists {
+        inner: InMemoryPersister<i64>,
+        delay: Duration,
+    }
+
+    impl Persister<i64> for SlowExists {
+        fn fence<'a>(&'a self, fence: Fence<i64>) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>>
+        where
+            i64: 'a,
+        {
+            self.inner.fence(fence)
+        }
+
+        fn insert<'a>(&'a self, loc: LocationCommand<i64>, fence: Fence<i64>) -> Pin<Box<dyn Future<Output = Result<String, Error>> + 'a>>
+        where
+            i64: 'a,
+        {
+            self.inner.insert(loc, fence)
+        }
+
+        fn update<'a>(&'a self, id: String, loc: LocationUpdate<i64>, fence: Fence<i64>) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>>
+        where
+            i64: 'a,
+        {
+            self.inner.update(id, loc, fence)
+        }
+
+        fn get<'a>(&'a self, id: String) -> Pin<Box<dyn Future<Output = Result<Option<Location<i64>>, Error>> + 'a>>
+        where
+            i64: 'a,
+        {
+            self.inner.get(id)
+        }
+
+        fn delete<'a>(&'a self, id: String) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + 'a>>
+        where
+            i64: 'a,
+        {
+            self.inner.delete(id)
+        }
+
+        fn list_by_owner<'a>(&'a self, uid: String, page: i64, size: i64) -> Pin<Box<dyn Future<Output = Result<(Vec<Location<i64>>, u64), Error>> + 'a>>
+        where
+            i64: 'a,
+        {
+            self.inner.list_by_owner(uid, page, size)
+        }
+
+        fn query<'a>(
+            &'a self,
+            ranges: Vec<RangeInclusive<i64>>,
+            latitude: f64,
+            longitude: f64,
+            distance: f64,
+            filter: LocationFilter,
+            page: i64,
+            size: i64,
+        ) -> Pin<Box<dyn Future<Output = Result<(Vec<LocationWithDistance<i64>>, u64), Error>> + 'a>>
+        where
+            i64: 'a,
+        {
+            self.inner.query(ranges, latitude, longitude, distance, filter, page, size)
+        }
+
+        fn query_within<'a>(
+            &'a self,
+            ranges: Vec<RangeInclusive<i64>>,
+            rect: Rect,
+            filter: LocationFilter,
+            page: i64,
+            size: i64,
+        ) -> Pin<Box<dyn Future<Output = Result<(Vec<Location<i64>>, u64), Error>> + 'a>>
+        where
+            i64: 'a,
+        {
+            self.inner.query_within(ranges, rect, filter, page, size)
+        }
+
+        fn exists<'a>(&'a self, indices: Vec<i64>, latitude: f64, longitude: f64, distance: f64, exclude: Option<String>) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + 'a>>
+        where
+            i64: 'a,
+        {
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                self.inner.exists(indices, latitude, longitude, distance, exclude).await
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lock_expired_before_write() {
+        // 第一次延长(获取锁1秒后)就失败, 此时重复检查还没有结束
+        let mutex = TestMutex {
+            extended: Arc::new(AtomicUsize::new(0)),
+            fail_at: 1,
+            release_fails: false,
+        };
+        let indexer = H3Indexer::new(vec![6, 7, 8], 100).unwrap();
+        let level = indexer.level(500.0);
+        let persister = SlowExists {
+            inner: InMemoryPersister::new(),
+            delay: Duration::from_secs(2),
+        };
+        match add_location(
+            mutex.clone(),
+            indexer.clone(),
+            persister.clone(),
+            36.657004,
+            117.0242607,
+            500.0,
+            level,
+            "1".into(),
+            LocationAttributes::default(),
+        )
+        .await
+        {
+            Err(Error::LockExpired) => {}
+            _ => panic!("expected lock expired"),
+        }
+        assert_eq!(mutex.extended.load(Ordering::SeqCst), 1);
+        let (_, total) = persister.list_by_owner("1".into(), 1, 10).await.unwrap();
+        assert_eq!(total, 0);
+        // 修改同样在写入前放弃
+        let id = add_location(
+            LocalMutex::<i64>::new(1),
+            indexer.clone(),
+            persister.inner.clone(),
+            36.657004,
+            117.0242607,
+            500.0,
+            level,
+            "1".into(),
+            LocationAttributes::default(),
+        )
+        .await
+        .unwrap();
+        match update_location(mutex, indexer, persister.clone(), id.clone(), 36.657504, 117.0242607, 500.0, level).await {
+            Err(Error::LockExpired) => {}
+            _ => panic!("expected lock expired"),
+        }
+        assert_eq!(persister.get(id).await.unwrap().unwrap().latitude, 36.657004);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lock_guard_released_on_drop() {
+        let mutex = LocalMutex::<i64>::new(1);
+        let guard = mutex.clone().lock(vec![1, 2]).await.unwrap();
+        match mutex.clone().lock(vec![2]).await {
+            Err(Error::LockTimeout) => {}
+            _ => panic!("expected lock timeout"),
+        }
+        drop(guard);
+        let guard = mutex.clone().lock(vec![1, 2]).await.unwrap();
+        guard.release().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_add_and_update_location() {
+        let mutex = LocalMutex::<i64>::new(1);
+        let indexer = H3Indexer::new(vec![6, 7, 8], 100).unwrap();
+        let level = indexer.level(500.0);
+        let persister = InMemoryPersister::<i64>::new();
+        let id = add_location(
+            mutex.clone(),
+            indexer.clone(),
+            persister.clone(),
+            36.657004,
+            117.0242607,
+            500.0,
+            level,
+            "1".into(),
+            LocationAttributes::default(),
+        )
+        .await
+        .unwrap();
+        // 500米内已存在地点
+        match add_location(
+            mutex.clone(),
+            indexer.clone(),
+            persister.clone(),
+            36.658004,
+            117.0242607,
+            500.0,
+            level,
+            "2".into(),
+            LocationAttributes::default(),
+        )
+        .await
+        {
+            Err(Error::Conflict(_)) => {}
+            _ => panic!("expected conflict"),
+        }
+        let other = add_location(
+            mutex.clone(),
+            indexer.clone(),
+            persister.clone(),
+            36.667004,
+            117.0242607,
+            500.0,
+            level,
+            "2".into(),
+            LocationAttributes::default(),
+        )
+        .await
+        .unwrap();
+        // 移动到另一个地点附近时冲突, 在原地附近小范围移动时不与自身冲突
+        match update_location(mutex.clone(), indexer.clone(), persister.clone(), id.clone(), 36.666004, 117.0242607, 500.0, level).await {
+            Err(Error::Conflict(_)) => {}
+            _ => panic!("expected conflict"),
+        }
+        update_location(mutex.clone(), indexer.clone(), persister.clone(), id.clone(), 36.657504, 117.0242607, 500.0, level)
+            .await
+            .unwrap();
+        let (locs, total) = nearby_locations(&indexer, &persister, 36.657004, 117.0242607, 2000.0, LocationFilter::default(), 1, 10).await.unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(locs[0].location.id, id);
+        assert_eq!(locs[0].location.latitude, 36.657504);
+        assert_eq!(locs[1].location.id, other);
+        // 大半径的搜索使用较粗的层, 同样能找到按细层加锁写入的地点
+        assert!(indexer.level(20000.0) < indexer.level(500.0));
+        let (_, total) = nearby_locations(&indexer, &persister, 36.757004, 117.0242607, 20000.0, LocationFilter::default(), 1, 10).await.unwrap();
+        assert_eq!(total, 2);
+        match update_location(mutex, indexer, persister, "unknown".into(), 36.657004, 117.0242607, 500.0, level).await {
+            Err(Error::NotFound(_)) => {}
+            _ => panic!("expected not found"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_locations_within() {
+        let indexer = H3Indexer::new(vec![5, 6, 7, 8], 100).unwrap();
+        let level = indexer.level(500.0);
+        let persister = InMemoryPersister::<i64>::new();
+        let mut ids = Vec::new();
+        for (latitude, longitude) in [(36.657004, 117.0242607), (36.757004, 117.3242607), (37.157004, 117.0242607)] {
+            ids.push(
+                add_location_unique(indexer.clone(), persister.clone(), latitude, longitude, 500.0, level, "1".into(), LocationAttributes::default())
+                    .await
+                    .unwrap(),
+            );
+        }
+        // 较大的矩形使用较粗的层查询, 矩形外的地点被排除
+        let rect = Rect {
+            south: 36.5,
+            west: 116.8,
+            north: 37.0,
+            east: 117.5,
+        };
+        assert!(indexer.level(rect.radius()) < 3);
+        let (locs, total) = locations_within(&indexer, &persister, rect, LocationFilter::default(), 1, 10).await.unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(locs.iter().map(|l| l.id.clone()).collect::<Vec<_>>(), vec![ids[1].clone(), ids[0].clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_add_and_update_location_unique() {
+        let indexer = H3Indexer::new(vec![6, 7, 8], 100).unwrap();
+        let level = indexer.level(500.0);
+        let persister = InMemoryPersister::<i64>::new();
+        // 并发添加相邻的两个地点时只有一个能成功
+        let (a, b) = tokio::join!(
+            add_location_unique(indexer.clone(), persister.clone(), 36.657004, 117.0242607, 500.0, level, "1".into(), LocationAttributes::default()),
+            add_location_unique(indexer.clone(), persister.clone(), 36.658004, 117.0242607, 500.0, level, "2".into(), LocationAttributes::default()),
+        );
+        assert!(a.is_ok() != b.is_ok());
+        let id = a.or(b).unwrap();
+        let other = add_location_unique(indexer.clone(), persister.clone(), 36.667004, 117.0242607, 500.0, level, "2".into(), LocationAttributes::default())
+            .await
+            .unwrap();
+        match update_location_unique(indexer.clone(), persister.clone(), other, 36.661004, 117.0242607, 500.0, level).await {
+            Err(Error::Conflict(_)) => {}
+            _ => panic!("expected conflict"),
+        }
+        update_location_unique(indexer.clone(), persister.clone(), id.clone(), 36.657504, 117.0242607, 500.0, level)
+            .await
+            .unwrap();
+        assert_eq!(persister.get(id).await.unwrap().unwrap().latitude, 36.657504);
+    }
+}
@@ -1,27 +1,193 @@
-use std::fmt::Display;
-
-use actix_web::body::BoxBody;
 use actix_web::http::StatusCode;
 use actix_web::HttpResponse;
 use actix_web::ResponseError;
+use log::error;
+use mongodb::error::ErrorKind;
+use serde::Serialize;
+
+use crate::validation::FieldError;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    #[error("invalid parameters: {}", .0.iter().map(|e| format!("{}: {}", e.field, e.message)).collect::<Vec<_>>().join("; "))]
+    Validation(Vec<FieldError>),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("timeout while acquiring lock")]
+    LockTimeout,
+    #[error("lock expired before the write was done")]
+    LockExpired,
+    #[error("backend unavailable: {0}")]
+    BackendUnavailable(String),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl Error {
+    // 供客户端判断错误类型的稳定错误码, 不要随意修改
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Error::Validation(_) => "INVALID_PARAMETER",
+            Error::Conflict(_) => "CONFLICT",
+            Error::NotFound(_) => "NOT_FOUND",
+            Error::Forbidden(_) => "FORBIDDEN",
+            Error::LockTimeout => "LOCK_TIMEOUT",
+            Error::LockExpired => "LOCK_EXPIRED",
+            Error::BackendUnavailable(_) => "BACKEND_UNAVAILABLE",
+            Error::Internal(_) => "INTERNAL_ERROR",
+        }
+    }
+}
 
-#[derive(Debug)]
-pub(crate) struct Error(anyhow::Error);
+impl From<mongodb::error::Error> for Error {
+    fn from(e: mongodb::error::Error) -> Self {
+        match *e.kind {
+            ErrorKind::ServerSelection { .. } | ErrorKind::Io(_) | ErrorKind::ConnectionPoolCleared { .. } | ErrorKind::DnsResolve { .. } => Error::BackendUnavailable(e.to_string()),
+            _ => Error::Internal(e.into()),
+        }
+    }
+}
 
-impl From<anyhow::Error> for Error {
-    fn from(e: anyhow::Error) -> Self {
-        Self(e)
+impl From<mongodb::bson::de::Error> for Error {
+    fn from(e: mongodb::bson::de::Error) -> Self {
+        Error::Internal(e.into())
     }
 }
 
-impl Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+impl From<mongodb::bson::ser::Error> for Error {
+    fn from(e: mongodb::bson::ser::Error) -> Self {
+        Error::Internal(e.into())
     }
 }
 
+impl From<mongodb::bson::document::ValueAccessError> for Error {
+    fn from(e: mongodb::bson::document::ValueAccessError) -> Self {
+        Error::Internal(e.into())
+    }
+}
+
+impl From<redis::RedisError> for Error {
+    fn from(e: redis::RedisError) -> Self {
+        if e.is_io_error() || e.is_connection_refusal() || e.is_connection_dropped() || e.is_timeout() {
+            return Error::BackendUnavailable(e.to_string());
+        }
+        Error::Internal(e.into())
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+}
+
 impl ResponseError for Error {
-    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
-        HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR).set_body(BoxBody::new(format!("{}", self)))
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::Validation(_) => StatusCode::BAD_REQUEST,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::LockTimeout | Error::LockExpired | Error::BackendUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let Error::Internal(e) = self {
+            error!("{e:?}");
+        }
+        HttpResponse::build(self.status_code()).json(ErrorResponse {
+            code: self.code(),
+            message: self.to_string(),
+            fields: match self {
+                Error::Validation(fields) => fields.clone(),
+                _ => Vec::new(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handlers::{json_error_handler, query_error_handler, Pagination};
+    use actix_web::error::JsonPayloadError;
+    use actix_web::test::TestRequest;
+    use actix_web::web::Query;
+    use serde_json::{json, Value};
+
+    async fn body(res: HttpResponse) -> Value {
+        let bytes = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_error_response() {
+        let cases = [
+            (Error::Conflict("already exists location nearby".into()), StatusCode::CONFLICT, "CONFLICT"),
+            (Error::NotFound("location not found: 1".into()), StatusCode::NOT_FOUND, "NOT_FOUND"),
+            (Error::Forbidden("forbidden".into()), StatusCode::FORBIDDEN, "FORBIDDEN"),
+            (Error::LockTimeout, StatusCode::SERVICE_UNAVAILABLE, "LOCK_TIMEOUT"),
+            (Error::LockExpired, StatusCode::SERVICE_UNAVAILABLE, "LOCK_EXPIRED"),
+            (Error::BackendUnavailable("redis".into()), StatusCode::SERVICE_UNAVAILABLE, "BACKEND_UNAVAILABLE"),
+            (Error::Internal(anyhow::anyhow!("boom")), StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_ERROR"),
+        ];
+        for (e, status, code) in cases {
+            let message = e.to_string();
+            let res = e.error_response();
+            assert_eq!(res.status(), status);
+            // 没有字段错误时不返回fields
+            assert_eq!(body(res).await, json!({"code": code, "message": message}));
+        }
+        let e = Error::Validation(vec![
+            FieldError {
+                field: "latitude",
+                message: "must be a finite number between -90 and 90".into(),
+            },
+            FieldError {
+                field: "size",
+                message: "must be between 1 and 100".into(),
+            },
+        ]);
+        let res = e.error_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body(res).await,
+            json!({
+                "code": "INVALID_PARAMETER",
+                "message": "invalid parameters: latitude: must be a finite number between -90 and 90; size: must be between 1 and 100",
+                "fields": [
+                    {"field": "latitude", "message": "must be a finite number between -90 and 90"},
+                    {"field": "size", "message": "must be between 1 and 100"},
+                ],
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_payload_error_handlers() {
+        let req = TestRequest::default().to_http_request();
+        let res = json_error_handler(JsonPayloadError::ContentType, &req).error_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let v = body(res).await;
+        assert_eq!(v["code"], "INVALID_PARAMETER");
+        assert_eq!(v["fields"][0]["field"], "body");
+        let err = match Query::<Pagination>::from_query("page=first&size=10") {
+            Err(e) => e,
+            Ok(_) => panic!("expected query error"),
+        };
+        let res = query_error_handler(err, &req).error_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let v = body(res).await;
+        assert_eq!(v["code"], "INVALID_PARAMETER");
+        assert_eq!(v["fields"][0]["field"], "query");
+        assert_eq!(v["message"], format!("invalid parameters: query: {}", v["fields"][0]["message"].as_str().unwrap()));
     }
 }
@@ -0,0 +1,184 @@
+// 与mongodb球面距离计算使用的地球半径保持一致(米)
+pub(crate) const EARTH_RADIUS: f64 = 6378100.0;
+
+// 两点间的球面距离(米)
+pub(crate) fn haversine(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lon1, lat2, lon2) = (lat1.to_radians(), lon1.to_radians(), lat2.to_radians(), lon2.to_radians());
+    let a = ((lat2 - lat1) / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin()
+}
+
+// 单位球面上的点
+pub(crate) type Point = [f64; 3];
+
+pub(crate) fn dot(a: &Point, b: &Point) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub(crate) fn cross(a: &Point, b: &Point) -> Point {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+pub(crate) fn normalize(a: Point) -> Point {
+    let n = dot(&a, &a).sqrt();
+    [a[0] / n, a[1] / n, a[2] / n]
+}
+
+// 两个单位向量的夹角(弧度)
+pub(crate) fn angle(a: &Point, b: &Point) -> f64 {
+    let c = cross(a, b);
+    dot(&c, &c).sqrt().atan2(dot(a, b))
+}
+
+pub(crate) fn lat_lng_to_point(latitude: f64, longitude: f64) -> Point {
+    let (lat, lng) = (latitude.to_radians(), longitude.to_radians());
+    [lat.cos() * lng.cos(), lat.cos() * lng.sin(), lat.sin()]
+}
+
+// 点到大圆弧ab的最短距离(弧度)
+pub(crate) fn edge_distance(p: &Point, a: &Point, b: &Point) -> f64 {
+    let n = cross(a, b);
+    let unit = normalize(n);
+    let d = dot(p, &unit);
+    // p在大圆上的投影落在弧内时, 距离为p到大圆的距离, 否则为到较近端点的距离
+    let q = [p[0] - d * unit[0], p[1] - d * unit[1], p[2] - d * unit[2]];
+    if dot(&cross(a, &q), &n) > 0.0 && dot(&cross(&q, b), &n) > 0.0 {
+        return d.abs().min(1.0).asin();
+    }
+    angle(p, a).min(angle(p, b))
+}
+
+// 点到边为大圆弧的多边形边界的最短距离(弧度), 顶点按顺序给出
+pub(crate) fn boundary_distance(p: &Point, vertices: &[Point]) -> f64 {
+    (0..vertices.len())
+        .map(|k| edge_distance(p, &vertices[k], &vertices[(k + 1) % vertices.len()]))
+        .fold(f64::INFINITY, f64::min)
+}
+
+pub(crate) fn point_to_lat_lng(p: &Point) -> (f64, f64) {
+    (p[2].atan2(p[0].hypot(p[1])).to_degrees(), p[1].atan2(p[0]).to_degrees())
+}
+
+// 经纬度矩形(度), 南北边是纬线, 东西边是经线; west大于east时跨越180度经线
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Rect {
+    pub south: f64,
+    pub west: f64,
+    pub north: f64,
+    pub east: f64,
+}
+
+impl Rect {
+    // 经度方向的跨度(度)
+    pub(crate) fn width(&self) -> f64 {
+        (self.east - self.west).rem_euclid(360.0)
+    }
+
+    fn contains_lng(&self, longitude: f64) -> bool {
+        (longitude - self.west).rem_euclid(360.0) <= self.width()
+    }
+
+    pub(crate) fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        (self.south..=self.north).contains(&latitude) && self.contains_lng(longitude)
+    }
+
+    pub(crate) fn center(&self) -> (f64, f64) {
+        let longitude = self.west + self.width() / 2.0;
+        ((self.south + self.north) / 2.0, (longitude + 540.0).rem_euclid(360.0) - 180.0)
+    }
+
+    // 球面面积(平方米)
+    pub(crate) fn area(&self) -> f64 {
+        EARTH_RADIUS * EARTH_RADIUS * self.width().to_radians() * (self.north.to_radians().sin() - self.south.to_radians().sin())
+    }
+
+    fn corners(&self) -> [Point; 4] {
+        [
+            lat_lng_to_point(self.south, self.west),
+            lat_lng_to_point(self.south, self.east),
+            lat_lng_to_point(self.north, self.east),
+            lat_lng_to_point(self.north, self.west),
+        ]
+    }
+
+    // 中心到最远的角的距离(米), 以此为半径的圆覆盖整个矩形
+    pub(crate) fn radius(&self) -> f64 {
+        let (latitude, longitude) = self.center();
+        let center = lat_lng_to_point(latitude, longitude);
+        self.corners().iter().map(|c| angle(&center, c)).fold(0.0, f64::max) * EARTH_RADIUS
+    }
+
+    // 点到矩形的最短球面距离(弧度), 点在矩形内时为0
+    pub(crate) fn distance(&self, p: &Point) -> f64 {
+        let (latitude, longitude) = point_to_lat_lng(p);
+        if self.contains(latitude, longitude) {
+            return 0.0;
+        }
+        let [sw, se, ne, nw] = self.corners();
+        // 东西边是大圆弧; 到南北边(纬线)的最近点与p经度相同, 经度不在范围内时为两端的角
+        let mut d = edge_distance(p, &sw, &nw).min(edge_distance(p, &se, &ne));
+        if self.contains_lng(longitude) {
+            d = d.min((latitude - self.south).abs().to_radians()).min((latitude - self.north).abs().to_radians());
+        }
+        self.corners().iter().map(|c| angle(p, c)).fold(d, f64::min)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_haversine() {
+        assert_eq!(haversine(36.657004, 117.0242607, 36.657004, 117.0242607), 0.0);
+        // 纬度相差1度约为111.3公里
+        let d = haversine(36.0, 117.0, 37.0, 117.0);
+        assert!((d - 111318.0).abs() < 10.0, "{d}");
+        assert_eq!(haversine(36.0, 117.0, 37.0, 118.0), haversine(37.0, 118.0, 36.0, 117.0));
+    }
+
+    #[test]
+    fn test_edge_distance() {
+        let (a, b) = (lat_lng_to_point(0.0, 0.0), lat_lng_to_point(0.0, 10.0));
+        // 投影落在弧内时为到赤道的距离, 否则为到端点的距离
+        let d = edge_distance(&lat_lng_to_point(1.0, 5.0), &a, &b);
+        assert!((d - 1f64.to_radians()).abs() < 1e-12, "{d}");
+        let d = edge_distance(&lat_lng_to_point(0.0, 12.0), &a, &b);
+        assert!((d - 2f64.to_radians()).abs() < 1e-12, "{d}");
+        assert!((angle(&a, &b) * EARTH_RADIUS - haversine(0.0, 0.0, 0.0, 10.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_rect() {
+        let rect = Rect {
+            south: 36.0,
+            west: 117.0,
+            north: 37.0,
+            east: 118.0,
+        };
+        assert!(rect.contains(36.657004, 117.0242607));
+        assert!(!rect.contains(36.657004, 116.9));
+        assert_eq!(rect.distance(&lat_lng_to_point(36.5, 117.5)), 0.0);
+        // 正南方为到纬线的距离, 西南方为到角的距离
+        let d = rect.distance(&lat_lng_to_point(35.0, 117.5)) * EARTH_RADIUS;
+        assert!((d - haversine(35.0, 117.5, 36.0, 117.5)).abs() < 1e-6, "{d}");
+        let d = rect.distance(&lat_lng_to_point(35.0, 116.0)) * EARTH_RADIUS;
+        assert!((d - haversine(35.0, 116.0, 36.0, 117.0)).abs() < 1e-6, "{d}");
+        // 1度x1度约为111公里x89公里
+        assert!((rect.area() / 1e6 - 9900.0).abs() < 100.0, "{}", rect.area());
+        assert!(rect.radius() >= haversine(36.5, 117.5, 37.0, 118.0));
+        // 跨越180度经线
+        let rect = Rect {
+            south: -1.0,
+            west: 179.0,
+            north: 1.0,
+            east: -179.0,
+        };
+        assert_eq!(rect.width(), 2.0);
+        assert_eq!(rect.center(), (0.0, -180.0));
+        assert!(rect.contains(0.0, -179.5) && rect.contains(0.0, 179.5));
+        assert!(!rect.contains(0.0, 0.0));
+        let d = rect.distance(&lat_lng_to_point(0.0, -178.0)) * EARTH_RADIUS;
+        assert!((d - haversine(0.0, -178.0, 0.0, -179.0)).abs() < 1e-6, "{d}");
+    }
+}
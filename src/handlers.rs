@@ -1,10 +1,16 @@
-use crate::core::{self, Indexer, Key, Mutex, Persister};
+use crate::core::{self, Fenced, Indexer, Key, Mutex, Persister, UniquePersister};
 use crate::error::Error;
-use crate::models::Location;
+use crate::geo::Rect;
+use crate::models::{Location, LocationAttributes, LocationFilter, LocationWithDistance, TagsMatch};
+use crate::validation::{FieldError, Validate, Validator};
 use actix_header::actix_header;
-use actix_web::web::{Data, Header, Json, Query};
+use actix_web::error::{JsonPayloadError, QueryPayloadError};
+use actix_web::web::{Data, Header, Json, Path, Query};
+use actix_web::{HttpRequest, HttpResponse};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
+#[allow(clippy::upper_case_acronyms)]
 #[actix_header("UID")]
 pub struct UID(String);
 
@@ -20,52 +26,448 @@ impl From<UID> for String {
     }
 }
 
+#[derive(Debug, Clone)]
+pub(crate) struct RadiusConfig {
+    // 添加/修改地点时, 此半径(米)内已存在地点则视为重复
+    pub duplicate: f64,
+    // 附近地点默认的搜索半径(米)
+    pub search: f64,
+    // 请求中指定的搜索半径不能超过此值(米)
+    pub max_search: f64,
+    // 按分类覆盖duplicate和search, 没有配置的分类使用上面的默认值
+    pub duplicate_by_category: BTreeMap<String, f64>,
+    pub search_by_category: BTreeMap<String, f64>,
+}
+
+impl RadiusConfig {
+    fn duplicate_for(&self, category: Option<&str>) -> f64 {
+        category.and_then(|c| self.duplicate_by_category.get(c)).copied().unwrap_or(self.duplicate)
+    }
+
+    fn search_for(&self, category: Option<&str>) -> f64 {
+        category.and_then(|c| self.search_by_category.get(c)).copied().unwrap_or(self.search)
+    }
+
+    // 所有写操作都按最大的重复半径选择加锁的层, 保证不同分类的写入锁同一层的格子
+    fn lock_level<'a, K, I>(&self, indexer: &I) -> usize
+    where
+        K: Key<'a> + 'a,
+        I: Indexer<'a, K>,
+    {
+        indexer.level(self.duplicate_by_category.values().copied().fold(self.duplicate, f64::max))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BboxConfig {
+    // 矩形查询的最大面积(平方米)
+    pub max_area: f64,
+}
+
 #[derive(Deserialize)]
 pub(crate) struct AddLocation {
     latitude: f64,
     longitude: f64,
+    #[serde(flatten)]
+    attributes: LocationAttributes,
 }
 
-pub(crate) async fn add_location<K, I, M, P, L>(Header(UID(uid)): Header<UID>, Json(loc): Json<AddLocation>, indexer: Data<I>, mutex: Data<M>, persister: Data<P>) -> Result<Json<String>, Error>
+impl Validate for AddLocation {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::new()
+            .latitude("latitude", self.latitude)
+            .longitude("longitude", self.longitude)
+            .attributes(&self.attributes)
+            .finish()
+    }
+}
+
+pub(crate) async fn add_location<K, I, M, P, L>(
+    Header(UID(uid)): Header<UID>,
+    Json(loc): Json<AddLocation>,
+    indexer: Data<I>,
+    mutex: Data<M>,
+    persister: Data<P>,
+    radius: Data<RadiusConfig>,
+) -> Result<Json<String>, Error>
 where
     K: Key<'static> + 'static,
     I: Indexer<'static, K> + Clone + 'static,
-    M: Mutex<K, L> + Clone + 'static,
+    M: Mutex<K, L> + Clone + Send + 'static,
     P: Persister<K> + Clone + 'static,
-    L: 'static,
+    L: Fenced + Send + 'static,
 {
-    let res = core::add_location(mutex.get_ref().clone(), indexer.get_ref().clone(), persister.get_ref().clone(), loc.latitude, loc.longitude, 500.0, uid).await?;
+    loc.validate()?;
+    let res = core::add_location(
+        mutex.get_ref().clone(),
+        indexer.get_ref().clone(),
+        persister.get_ref().clone(),
+        loc.latitude,
+        loc.longitude,
+        radius.duplicate_for(loc.attributes.category.as_deref()),
+        radius.lock_level(indexer.as_ref()),
+        uid,
+        loc.attributes,
+    )
+    .await?;
+    Ok(Json(res))
+}
+
+// 无锁模式, 由存储层的事务保证附近不重复
+pub(crate) async fn add_location_unique<K, I, P>(
+    Header(UID(uid)): Header<UID>,
+    Json(loc): Json<AddLocation>,
+    indexer: Data<I>,
+    persister: Data<P>,
+    radius: Data<RadiusConfig>,
+) -> Result<Json<String>, Error>
+where
+    K: Key<'static> + 'static,
+    I: Indexer<'static, K> + Clone + 'static,
+    P: UniquePersister<K> + Clone + 'static,
+{
+    loc.validate()?;
+    let res = core::add_location_unique(
+        indexer.get_ref().clone(),
+        persister.get_ref().clone(),
+        loc.latitude,
+        loc.longitude,
+        radius.duplicate_for(loc.attributes.category.as_deref()),
+        radius.lock_level(indexer.as_ref()),
+        uid,
+        loc.attributes,
+    )
+    .await?;
     Ok(Json(res))
 }
 
+#[derive(Deserialize)]
+pub(crate) struct UpdateLocation {
+    id: String,
+    latitude: f64,
+    longitude: f64,
+}
+
+impl Validate for UpdateLocation {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::new()
+            .check("id", !self.id.is_empty(), "must not be empty")
+            .latitude("latitude", self.latitude)
+            .longitude("longitude", self.longitude)
+            .finish()
+    }
+}
+
+// 地点存在且属于当前用户时返回该地点
+async fn owned_location<'a, K, P>(persister: &P, id: &str, uid: &str) -> Result<Location<K>, Error>
+where
+    K: Key<'a> + 'a,
+    P: Persister<K>,
+{
+    let loc = persister.get(id.to_owned()).await?.ok_or_else(|| Error::NotFound(format!("location not found: {id}")))?;
+    if loc.uid != uid {
+        return Err(Error::Forbidden(format!("location {id} does not belong to current user")));
+    }
+    Ok(loc)
+}
+
+pub(crate) async fn update_location<K, I, M, P, L>(
+    Header(UID(uid)): Header<UID>,
+    Json(loc): Json<UpdateLocation>,
+    indexer: Data<I>,
+    mutex: Data<M>,
+    persister: Data<P>,
+    radius: Data<RadiusConfig>,
+) -> Result<Json<String>, Error>
+where
+    K: Key<'static> + 'static,
+    I: Indexer<'static, K> + Clone + 'static,
+    M: Mutex<K, L> + Clone + Send + 'static,
+    P: Persister<K> + Clone + 'static,
+    L: Fenced + Send + 'static,
+{
+    loc.validate()?;
+    let old = owned_location(persister.as_ref(), &loc.id, &uid).await?;
+    core::update_location(
+        mutex.get_ref().clone(),
+        indexer.get_ref().clone(),
+        persister.get_ref().clone(),
+        loc.id.clone(),
+        loc.latitude,
+        loc.longitude,
+        radius.duplicate_for(old.attributes.category.as_deref()),
+        radius.lock_level(indexer.as_ref()),
+    )
+    .await?;
+    Ok(Json(loc.id))
+}
+
+pub(crate) async fn update_location_unique<K, I, P>(
+    Header(UID(uid)): Header<UID>,
+    Json(loc): Json<UpdateLocation>,
+    indexer: Data<I>,
+    persister: Data<P>,
+    radius: Data<RadiusConfig>,
+) -> Result<Json<String>, Error>
+where
+    K: Key<'static> + 'static,
+    I: Indexer<'static, K> + Clone + 'static,
+    P: UniquePersister<K> + Clone + 'static,
+{
+    loc.validate()?;
+    let old = owned_location(persister.as_ref(), &loc.id, &uid).await?;
+    core::update_location_unique(
+        indexer.get_ref().clone(),
+        persister.get_ref().clone(),
+        loc.id.clone(),
+        loc.latitude,
+        loc.longitude,
+        radius.duplicate_for(old.attributes.category.as_deref()),
+        radius.lock_level(indexer.as_ref()),
+    )
+    .await?;
+    Ok(Json(loc.id))
+}
+
+pub(crate) async fn get_location<'a, K, P>(id: Path<String>, persister: Data<P>) -> Result<Json<Location<K>>, Error>
+where
+    K: Key<'a> + 'a,
+    P: Persister<K>,
+{
+    let id = id.into_inner();
+    let loc = persister.get(id.clone()).await?.ok_or_else(|| Error::NotFound(format!("location not found: {id}")))?;
+    Ok(Json(loc))
+}
+
+pub(crate) async fn delete_location<'a, K, P>(Header(UID(uid)): Header<UID>, id: Path<String>, persister: Data<P>) -> Result<HttpResponse, Error>
+where
+    K: Key<'a> + 'a,
+    P: Persister<K>,
+{
+    let id = id.into_inner();
+    owned_location(persister.as_ref(), &id, &uid).await?;
+    if !persister.delete(id.clone()).await? {
+        return Err(Error::NotFound(format!("location not found: {id}")));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Pagination {
+    page: i64,
+    size: i64,
+}
+
+impl Validate for Pagination {
+    fn validate(&self) -> Result<(), Error> {
+        Validator::new().page("page", self.page).size("size", self.size).finish()
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct MyLocationsResponse<I> {
+    list: Vec<Location<I>>,
+    total: u64,
+}
+
+pub(crate) async fn my_locations<'a, K, P>(Header(UID(uid)): Header<UID>, Query(query): Query<Pagination>, persister: Data<P>) -> Result<Json<MyLocationsResponse<K>>, Error>
+where
+    K: Key<'a> + 'a,
+    P: Persister<K>,
+{
+    query.validate()?;
+    let (locs, total) = persister.list_by_owner(uid, query.page, query.size).await?;
+    Ok(Json(MyLocationsResponse { list: locs, total }))
+}
+
 #[derive(Deserialize)]
 pub(crate) struct NearbyLocation {
     latitude: f64,
     longitude: f64,
     page: i64,
     size: i64,
+    radius: Option<f64>,
+    category: Option<String>,
+    // 逗号分隔的标签列表
+    tags: Option<String>,
+    #[serde(default)]
+    tags_match: TagsMatch,
+}
+
+// 逗号分隔的tags拆分为标签列表
+fn location_filter(category: &Option<String>, tags: &Option<String>, tags_match: TagsMatch) -> LocationFilter {
+    LocationFilter {
+        category: category.clone(),
+        tags: tags
+            .as_deref()
+            .map(|t| t.split(',').map(str::trim).filter(|t| !t.is_empty()).map(str::to_owned).collect())
+            .unwrap_or_default(),
+        tags_match,
+    }
+}
+
+impl NearbyLocation {
+    fn filter(&self) -> LocationFilter {
+        location_filter(&self.category, &self.tags, self.tags_match)
+    }
+
+    fn validate_with(&self, config: &RadiusConfig) -> Result<(), Error> {
+        let mut validator = Validator::new();
+        validator
+            .latitude("latitude", self.latitude)
+            .longitude("longitude", self.longitude)
+            .page("page", self.page)
+            .size("size", self.size);
+        if let Some(radius) = self.radius {
+            validator.check(
+                "radius",
+                radius.is_finite() && radius > 0.0 && radius <= config.max_search,
+                format!("must be greater than 0 and less than or equal to {}", config.max_search),
+            );
+        }
+        validator.attributes(&LocationAttributes {
+            category: self.category.clone(),
+            tags: self.filter().tags,
+            ..Default::default()
+        });
+        validator.finish()
+    }
 }
 
 #[derive(Serialize)]
 pub(crate) struct NearbyLocationsResponse<I> {
+    list: Vec<LocationWithDistance<I>>,
+    total: u64,
+}
+
+pub(crate) async fn nearby_locations<'a, K, I, P>(
+    Query(query): Query<NearbyLocation>,
+    indexer: Data<I>,
+    persister: Data<P>,
+    radius: Data<RadiusConfig>,
+) -> Result<Json<NearbyLocationsResponse<K>>, Error>
+where
+    K: Key<'a> + 'a,
+    I: Indexer<'a, K>,
+    P: Persister<K>,
+{
+    query.validate_with(&radius)?;
+    let distance = query.radius.unwrap_or_else(|| radius.search_for(query.category.as_deref()));
+    let (locs, total) = core::nearby_locations(indexer.as_ref(), persister.as_ref(), query.latitude, query.longitude, distance, query.filter(), query.page, query.size).await?;
+    Ok(Json(NearbyLocationsResponse { list: locs, total }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct BboxLocation {
+    min_lat: f64,
+    min_lon: f64,
+    max_lat: f64,
+    max_lon: f64,
+    page: i64,
+    size: i64,
+    category: Option<String>,
+    tags: Option<String>,
+    #[serde(default)]
+    tags_match: TagsMatch,
+}
+
+impl BboxLocation {
+    fn filter(&self) -> LocationFilter {
+        location_filter(&self.category, &self.tags, self.tags_match)
+    }
+
+    // min_lon大于max_lon时表示跨越180度经线
+    fn rect(&self) -> Rect {
+        Rect {
+            south: self.min_lat,
+            west: self.min_lon,
+            north: self.max_lat,
+            east: self.max_lon,
+        }
+    }
+
+    fn validate_with(&self, config: &BboxConfig) -> Result<(), Error> {
+        let mut validator = Validator::new();
+        validator
+            .latitude("min_lat", self.min_lat)
+            .longitude("min_lon", self.min_lon)
+            .latitude("max_lat", self.max_lat)
+            .longitude("max_lon", self.max_lon)
+            .page("page", self.page)
+            .size("size", self.size);
+        validator.check("max_lat", self.max_lat > self.min_lat, "must be greater than min_lat");
+        validator.check("max_lon", self.max_lon != self.min_lon, "must not be equal to min_lon");
+        let area = self.rect().area();
+        validator.check(
+            "bbox",
+            area.is_nan() || area <= config.max_area,
+            format!("area must be less than or equal to {} square kilometers", config.max_area / 1e6),
+        );
+        validator.attributes(&LocationAttributes {
+            category: self.category.clone(),
+            tags: self.filter().tags,
+            ..Default::default()
+        });
+        validator.finish()
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct BboxLocationsResponse<I> {
     list: Vec<Location<I>>,
     total: u64,
 }
 
-pub(crate) async fn nearby_locations<'a, K, I, P>(Query(query): Query<NearbyLocation>, indexer: Data<I>, persister: Data<P>) -> Result<Json<NearbyLocationsResponse<K>>, Error>
+pub(crate) async fn bbox_locations<'a, K, I, P>(Query(query): Query<BboxLocation>, indexer: Data<I>, persister: Data<P>, bbox: Data<BboxConfig>) -> Result<Json<BboxLocationsResponse<K>>, Error>
 where
     K: Key<'a> + 'a,
     I: Indexer<'a, K>,
     P: Persister<K>,
 {
-    let (locs, total) = core::nearby_locations(indexer.as_ref(), persister.as_ref(), query.latitude, query.longitude, 20000.0, query.page, query.size).await?;
-    Ok(Json(NearbyLocationsResponse { list: locs, total: total }))
+    query.validate_with(&bbox)?;
+    let (locs, total) = core::locations_within(indexer.as_ref(), persister.as_ref(), query.rect(), query.filter(), query.page, query.size).await?;
+    Ok(Json(BboxLocationsResponse { list: locs, total }))
+}
+
+pub(crate) fn json_error_handler(err: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    Error::Validation(vec![FieldError {
+        field: "body",
+        message: err.to_string(),
+    }])
+    .into()
+}
+
+pub(crate) fn query_error_handler(err: QueryPayloadError, _: &HttpRequest) -> actix_web::Error {
+    Error::Validation(vec![FieldError {
+        field: "query",
+        message: err.to_string(),
+    }])
+    .into()
 }
 
 #[cfg(test)]
 mod test {
-    use super::*;
+    use super::{
+        add_location, bbox_locations, delete_location, get_location, json_error_handler, my_locations, nearby_locations, query_error_handler, update_location, BboxConfig, BboxLocation, RadiusConfig,
+        TagsMatch,
+    };
+    use crate::core::Indexer;
+    use crate::error::Error;
+    use crate::indexers::H3Indexer;
+    use crate::mutexes::{LocalLock, LocalMutex};
+    use crate::persisters::InMemoryPersister;
+    use crate::validation::MAX_PAGE_SIZE;
     use actix_header::actix_header;
+    use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+    use actix_web::http::header::Header;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::web::{delete, get, post, put, Data, JsonConfig, QueryConfig};
+    use actix_web::App;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    type P = InMemoryPersister<i64>;
 
     #[actix_header("X-CUSTOMIZED-HEADER")]
     struct MyCustomizedHeader(String);
@@ -87,4 +489,275 @@ mod test {
         let name = MyCustomizedHeader::name();
         println!("{}", name)
     }
+
+    fn bbox(min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> BboxLocation {
+        BboxLocation {
+            min_lat,
+            min_lon,
+            max_lat,
+            max_lon,
+            page: 1,
+            size: 10,
+            category: None,
+            tags: None,
+            tags_match: TagsMatch::Any,
+        }
+    }
+
+    #[test]
+    fn test_bbox_validate() {
+        let config = BboxConfig { max_area: 10000.0 * 1e6 };
+        assert!(bbox(36.6, 117.0, 36.7, 117.1).validate_with(&config).is_ok());
+        // 跨越180度经线
+        assert!(bbox(-0.1, 179.9, 0.1, -179.9).validate_with(&config).is_ok());
+        let fields = |b: BboxLocation| match b.validate_with(&config) {
+            Err(Error::Validation(fields)) => fields.iter().map(|f| f.field).collect::<Vec<_>>(),
+            _ => panic!("expected validation error"),
+        };
+        assert_eq!(fields(bbox(36.7, 117.0, 36.6, 117.0)), vec!["max_lat", "max_lon"]);
+        assert_eq!(fields(bbox(36.0, 117.0, 38.0, 119.0)), vec!["bbox"]);
+        assert_eq!(fields(bbox(-91.0, 117.0, -89.5, 117.1)), vec!["min_lat"]);
+    }
+
+    // 与main.rs中的加锁模式相同的路由, 使用内存存储和进程内的锁
+    fn app() -> App<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse, Error = actix_web::Error, InitError = ()>> {
+        App::new()
+            .route("/locations", post().to(add_location::<i64, H3Indexer, LocalMutex<i64>, P, LocalLock<i64>>))
+            .route("/locations", put().to(update_location::<i64, H3Indexer, LocalMutex<i64>, P, LocalLock<i64>>))
+            .route("/locations", get().to(nearby_locations::<i64, H3Indexer, P>))
+            // 必须在/locations/{id}之前注册
+            .route("/locations/bbox", get().to(bbox_locations::<i64, H3Indexer, P>))
+            .route("/locations/{id}", get().to(get_location::<i64, P>))
+            .route("/locations/{id}", delete().to(delete_location::<i64, P>))
+            .route("/users/me/locations", get().to(my_locations::<i64, P>))
+            .app_data(JsonConfig::default().error_handler(json_error_handler))
+            .app_data(QueryConfig::default().error_handler(query_error_handler))
+            .app_data(Data::new(H3Indexer::new(vec![6, 7, 8], 100).unwrap()))
+            .app_data(Data::new(LocalMutex::<i64>::new(1)))
+            .app_data(Data::new(P::new()))
+            .app_data(Data::new(RadiusConfig {
+                duplicate: 500.0,
+                search: 20000.0,
+                max_search: 50000.0,
+                duplicate_by_category: BTreeMap::from([("restroom".to_owned(), 100.0)]),
+                search_by_category: BTreeMap::new(),
+            }))
+            .app_data(Data::new(BboxConfig { max_area: 10000.0 * 1e6 }))
+    }
+
+    fn add(uid: &str, latitude: f64, longitude: f64) -> TestRequest {
+        TestRequest::post()
+            .uri("/locations")
+            .insert_header(("UID", uid))
+            .set_json(json!({"latitude": latitude, "longitude": longitude}))
+    }
+
+    #[actix_web::test]
+    async fn test_update_location() {
+        let app = test::init_service(app()).await;
+        let id: String = test::call_and_read_body_json(&app, add("1", 36.657004, 117.0242607).to_request()).await;
+        let update = |uid: &str, id: &str| {
+            TestRequest::put()
+                .uri("/locations")
+                .insert_header(("UID", uid))
+                .set_json(json!({"id": id, "latitude": 36.657504, "longitude": 117.0242607}))
+                .to_request()
+        };
+        // 只能修改自己添加的地点
+        assert_eq!(test::call_service(&app, update("2", &id)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(test::call_service(&app, update("1", "62f1d5b1a8e1c2d3e4f5a6b7")).await.status(), StatusCode::NOT_FOUND);
+        let res = test::call_service(&app, update("1", &id)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body_json::<String, _>(res).await, id);
+        // 缺少UID时拒绝
+        let req = TestRequest::put()
+            .uri("/locations")
+            .set_json(json!({"id": id, "latitude": 36.657504, "longitude": 117.0242607}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_delete_location() {
+        let app = test::init_service(app()).await;
+        let id: String = test::call_and_read_body_json(&app, add("1", 36.657004, 117.0242607).to_request()).await;
+        let delete = |uid: &str, id: &str| TestRequest::delete().uri(&format!("/locations/{id}")).insert_header(("UID", uid)).to_request();
+        let res = test::call_service(&app, delete("2", &id)).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(test::read_body_json::<serde_json::Value, _>(res).await["code"], "FORBIDDEN");
+        let res = test::call_service(&app, delete("1", &id)).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(test::read_body(res).await.is_empty());
+        // 已删除的地点和非法的ID都返回404
+        assert_eq!(test::call_service(&app, delete("1", &id)).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(test::call_service(&app, delete("1", "invalid")).await.status(), StatusCode::NOT_FOUND);
+        // 删除后同一位置可以重新添加
+        let res = test::call_service(&app, add("2", 36.657004, 117.0242607).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_get_location() {
+        let app = test::init_service(app()).await;
+        let id: String = test::call_and_read_body_json(&app, add("1", 36.657004, 117.0242607).to_request()).await;
+        let get = |id: &str| TestRequest::get().uri(&format!("/locations/{id}")).to_request();
+        let loc: serde_json::Value = test::call_and_read_body_json(&app, get(&id)).await;
+        assert_eq!(loc["id"], id.as_str());
+        assert_eq!(loc["uid"], "1");
+        // 非法的ObjectId和不存在的ID都返回404而不是500
+        for id in ["invalid", "62f1d5b1a8e1c2d3e4f5a6b7"] {
+            let res = test::call_service(&app, get(id)).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            assert_eq!(test::read_body_json::<serde_json::Value, _>(res).await["code"], "NOT_FOUND");
+        }
+    }
+
+    #[test]
+    fn test_radius_config() {
+        let config = RadiusConfig {
+            duplicate: 500.0,
+            search: 20000.0,
+            max_search: 50000.0,
+            duplicate_by_category: BTreeMap::from([("restroom".to_owned(), 100.0), ("park".to_owned(), 2000.0)]),
+            search_by_category: BTreeMap::from([("restroom".to_owned(), 3000.0)]),
+        };
+        assert_eq!(config.duplicate_for(None), 500.0);
+        assert_eq!(config.duplicate_for(Some("restroom")), 100.0);
+        assert_eq!(config.duplicate_for(Some("nursing-room")), 500.0);
+        assert_eq!(config.search_for(Some("restroom")), 3000.0);
+        assert_eq!(config.search_for(Some("park")), 20000.0);
+        // 加锁的层由最大的重复半径决定
+        let indexer = H3Indexer::new(vec![6, 7, 8], 100).unwrap();
+        assert_eq!(config.lock_level(&indexer), indexer.level(2000.0));
+    }
+
+    #[actix_web::test]
+    async fn test_add_location_with_category_radius() {
+        let app = test::init_service(app()).await;
+        test::call_and_read_body_json::<_, _, String>(&app, add("1", 36.657004, 117.0242607).to_request()).await;
+        // 约300米外: 默认的500米内重复, 配置为100米的分类不重复
+        let res = test::call_service(&app, add("1", 36.659704, 117.0242607).to_request()).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let req = TestRequest::post()
+            .uri("/locations")
+            .insert_header(("UID", "1"))
+            .set_json(json!({"latitude": 36.659704, "longitude": 117.0242607, "category": "restroom"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_my_locations() {
+        let app = test::init_service(app()).await;
+        let mut ids = Vec::new();
+        for k in 0..3 {
+            let id: String = test::call_and_read_body_json(&app, add("1", 36.657004 + k as f64 * 0.1, 117.0242607).to_request()).await;
+            ids.push(id);
+        }
+        test::call_and_read_body_json::<_, _, String>(&app, add("2", 37.657004, 117.0242607).to_request()).await;
+        let list = |uid: &str, query: &str| TestRequest::get().uri(&format!("/users/me/locations?{query}")).insert_header(("UID", uid)).to_request();
+        // 只返回当前用户的地点, 由新到旧排序
+        let res: serde_json::Value = test::call_and_read_body_json(&app, list("1", "page=1&size=2")).await;
+        assert_eq!(res["total"], 3);
+        assert_eq!(
+            res["list"].as_array().unwrap().iter().map(|l| l["id"].as_str().unwrap()).collect::<Vec<_>>(),
+            vec![ids[2].as_str(), ids[1].as_str()]
+        );
+        let res: serde_json::Value = test::call_and_read_body_json(&app, list("1", "page=2&size=2")).await;
+        assert_eq!(res["list"].as_array().unwrap().len(), 1);
+        assert_eq!(res["list"][0]["id"], ids[0].as_str());
+        let res: serde_json::Value = test::call_and_read_body_json(&app, list("3", "page=1&size=10")).await;
+        assert_eq!(res["total"], 0);
+        // 页码和每页记录数越界
+        for query in [
+            "page=0&size=10",
+            "page=1&size=0",
+            &format!("page=1&size={}", MAX_PAGE_SIZE + 1),
+            &format!("page={}&size={MAX_PAGE_SIZE}", i64::MAX),
+        ] {
+            let res = test::call_service(&app, list("1", query)).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{query}");
+            assert_eq!(test::read_body_json::<serde_json::Value, _>(res).await["code"], "INVALID_PARAMETER");
+        }
+        let res = test::call_service(&app, list("1", &format!("page=1&size={MAX_PAGE_SIZE}"))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    // 列表中各地点的ID
+    fn list_ids(res: &serde_json::Value) -> Vec<String> {
+        res["list"].as_array().unwrap().iter().map(|l| l["id"].as_str().unwrap().to_owned()).collect()
+    }
+
+    #[actix_web::test]
+    async fn test_nearby_locations() {
+        let app = test::init_service(app()).await;
+        let mut ids = Vec::new();
+        // 由近到远约0, 1.1, 2.2公里
+        for (k, attrs) in [
+            json!({"category": "nursing-room", "tags": ["wifi", "parking"]}),
+            json!({"category": "restroom", "tags": ["wifi"]}),
+            json!({"tags": ["parking"]}),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let mut body = json!({"latitude": 36.657004 + k as f64 * 0.01, "longitude": 117.0242607});
+            body.as_object_mut().unwrap().extend(attrs.as_object().unwrap().clone());
+            let req = TestRequest::post().uri("/locations").insert_header(("UID", "1")).set_json(body).to_request();
+            let id: String = test::call_and_read_body_json(&app, req).await;
+            ids.push(id);
+        }
+        let nearby = |query: &str| {
+            TestRequest::get()
+                .uri(&format!("/locations?latitude=36.657004&longitude=117.0242607&page=1&size=10{query}"))
+                .to_request()
+        };
+        let res: serde_json::Value = test::call_and_read_body_json(&app, nearby("")).await;
+        assert_eq!(res["total"], 3);
+        assert_eq!(list_ids(&res), ids);
+        let res: serde_json::Value = test::call_and_read_body_json(&app, nearby("&category=restroom")).await;
+        assert_eq!(res["total"], 1);
+        assert_eq!(list_ids(&res), vec![ids[1].clone()]);
+        // 逗号分隔的标签去掉首尾空白, 默认包含任意一个即可
+        let res: serde_json::Value = test::call_and_read_body_json(&app, nearby("&tags=%20wifi%20,,parking%20")).await;
+        assert_eq!(res["total"], 3);
+        let res: serde_json::Value = test::call_and_read_body_json(&app, nearby("&tags=parking&tags_match=any")).await;
+        assert_eq!(list_ids(&res), vec![ids[0].clone(), ids[2].clone()]);
+        let res: serde_json::Value = test::call_and_read_body_json(&app, nearby("&tags=%20wifi%20,,parking%20&tags_match=all")).await;
+        assert_eq!(res["total"], 1);
+        assert_eq!(list_ids(&res), vec![ids[0].clone()]);
+        // 指定的半径覆盖默认的搜索半径, 但不能超过最大搜索半径
+        let res: serde_json::Value = test::call_and_read_body_json(&app, nearby("&radius=1500")).await;
+        assert_eq!(list_ids(&res), ids[..2].to_vec());
+        let res = test::call_service(&app, nearby("&radius=50001")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(res["code"], "INVALID_PARAMETER");
+        assert_eq!(res["fields"][0]["field"], "radius");
+        let res = test::call_service(&app, nearby("&tags_match=none")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_bbox_locations() {
+        let app = test::init_service(app()).await;
+        let mut ids = Vec::new();
+        for (latitude, longitude) in [(36.657004, 117.0242607), (36.667004, 117.0242607), (36.757004, 117.0242607)] {
+            let id: String = test::call_and_read_body_json(&app, add("1", latitude, longitude).to_request()).await;
+            ids.push(id);
+        }
+        let bbox = |query: &str| TestRequest::get().uri(&format!("/locations/bbox?{query}&page=1&size=10")).to_request();
+        // 矩形内的地点由新到旧排序
+        let res = test::call_service(&app, bbox("min_lat=36.6&min_lon=117.0&max_lat=36.7&max_lon=117.1")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(res["total"], 2);
+        assert_eq!(list_ids(&res), vec![ids[1].clone(), ids[0].clone()]);
+        // 面积超过上限
+        let res = test::call_service(&app, bbox("min_lat=36.0&min_lon=117.0&max_lat=38.0&max_lon=119.0")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(res["code"], "INVALID_PARAMETER");
+        assert_eq!(res["fields"][0]["field"], "bbox");
+    }
 }
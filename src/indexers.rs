@@ -1,53 +1,671 @@
 use crate::core::Indexer;
+use crate::geo::{angle, boundary_distance, lat_lng_to_point, normalize, Point, Rect, EARTH_RADIUS};
+use crate::s2::{self, CellId};
 use anyhow::Error;
-use libh3_sys::{degsToRads, edgeLengthKm, geoToH3, kRing, maxKringSize, GeoCoord, H3Index};
+use geohash::Coord;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::f64::consts::PI;
+use std::ops::RangeInclusive;
+#[cfg(not(any(feature = "h3-ffi", feature = "h3-pure")))]
+compile_error!("either feature \"h3-ffi\" or \"h3-pure\" must be enabled");
+
+#[cfg(feature = "h3-ffi")]
+#[cfg_attr(feature = "h3-pure", allow(dead_code))]
+mod h3_ffi {
+    use crate::geo::{lat_lng_to_point, Point};
+    use libh3_sys::{degsToRads, geoToH3, h3ToGeoBoundary, kRing, maxKringSize, radsToDegs, GeoBoundary, GeoCoord, H3Index};
+
+    pub(super) fn index(resolution: i32, latitude: f64, longitude: f64) -> i64 {
+        let coord = GeoCoord {
+            lat: unsafe { degsToRads(latitude) },
+            lon: unsafe { degsToRads(longitude) },
+        };
+        (unsafe { geoToH3(&coord as *const GeoCoord, resolution) }) as i64
+    }
+
+    pub(super) fn k_ring(index: i64, k: i32) -> Vec<i64> {
+        let mut res = vec![0u64; unsafe { maxKringSize(k) } as usize];
+        unsafe {
+            kRing(index as u64, k, &mut res[0] as *mut H3Index);
+        }
+        // 靠近五边形时结果少于maxKringSize, 空位为0
+        res.into_iter().filter(|&v| v != 0).map(|v| v as i64).collect()
+    }
+
+    pub(super) fn boundary(index: i64) -> Vec<Point> {
+        let mut boundary = GeoBoundary {
+            numVerts: 0,
+            verts: [GeoCoord { lat: 0.0, lon: 0.0 }; 10],
+        };
+        unsafe {
+            h3ToGeoBoundary(index as u64, &mut boundary as *mut GeoBoundary);
+        }
+        boundary.verts[..boundary.numVerts as usize]
+            .iter()
+            .map(|v| unsafe { lat_lng_to_point(radsToDegs(v.lat), radsToDegs(v.lon)) })
+            .collect()
+    }
+
+    // cells必须属于同一分辨率且互不重复
+    pub(super) fn compact(cells: &[i64]) -> Vec<i64> {
+        let set: Vec<H3Index> = cells.iter().map(|&c| c as u64).collect();
+        let mut res = vec![0u64; set.len()];
+        if unsafe { libh3_sys::compact(set.as_ptr(), res.as_mut_ptr(), set.len() as i32) } != 0 {
+            return cells.to_vec();
+        }
+        // 空位为0
+        res.into_iter().filter(|&v| v != 0).map(|v| v as i64).collect()
+    }
+}
+
+#[cfg(feature = "h3-pure")]
+mod h3_pure {
+    use crate::geo::{lat_lng_to_point, Point};
+    use h3o::{CellIndex, LatLng, Resolution};
+
+    pub(super) fn index(resolution: i32, latitude: f64, longitude: f64) -> i64 {
+        let resolution = Resolution::try_from(resolution as u8).unwrap();
+        u64::from(LatLng::new(latitude, longitude).unwrap().to_cell(resolution)) as i64
+    }
+
+    pub(super) fn k_ring(index: i64, k: i32) -> Vec<i64> {
+        let cell = CellIndex::try_from(index as u64).unwrap();
+        cell.grid_disk::<Vec<_>>(k as u32).into_iter().map(|c| u64::from(c) as i64).collect()
+    }
+
+    pub(super) fn boundary(index: i64) -> Vec<Point> {
+        let cell = CellIndex::try_from(index as u64).unwrap();
+        cell.boundary().iter().map(|v| lat_lng_to_point(v.lat(), v.lng())).collect()
+    }
+
+    // cells必须属于同一分辨率且互不重复
+    pub(super) fn compact(cells: &[i64]) -> Vec<i64> {
+        let set = cells.iter().map(|&c| CellIndex::try_from(c as u64).unwrap());
+        match CellIndex::compact(set) {
+            Ok(res) => res.map(|c| u64::from(c) as i64).collect(),
+            Err(_) => cells.to_vec(),
+        }
+    }
+}
+
+#[cfg(feature = "h3-pure")]
+use h3_pure as h3;
+
+#[cfg(all(feature = "h3-ffi", not(feature = "h3-pure")))]
+use h3_ffi as h3;
+
+// 由粗到细排序并去重, 至少需要一层
+fn sorted_levels<T: Ord>(mut levels: Vec<T>) -> Result<Vec<T>, Error> {
+    levels.sort();
+    levels.dedup();
+    if levels.is_empty() {
+        return Err(Error::msg("at least one level is required for indexer"));
+    }
+    Ok(levels)
+}
+
+// 与圆相交的格子都落在半径扩大一个格子直径的圆内, 用两者面积之比估计格子数的上限.
+// 由细到粗找到第一个估计值不超过max_cells的层, 都超过时使用最粗的一层.
+// 只与距离有关而与位置无关, 保证同一距离的写操作总是锁同一层的格子
+fn choose_level(count: usize, max_cells: usize, distance: f64, size: impl Fn(usize) -> (f64, f64)) -> usize {
+    (0..count)
+        .rev()
+        .find(|&level| {
+            let (area, diameter) = size(level);
+            PI * (distance + diameter).powi(2) / area <= max_cells as f64
+        })
+        .unwrap_or(0)
+}
+
+// 反复把同一个父格子的所有children个子格子替换为父格子, 直到不能再合并; parent返回None表示已是最粗的一层
+fn compact_by<T: Ord + Clone>(cells: Vec<T>, children: usize, parent: impl Fn(&T) -> Option<T>) -> Vec<T> {
+    let mut cells: BTreeSet<T> = cells.into_iter().collect();
+    loop {
+        let mut groups: BTreeMap<T, Vec<T>> = BTreeMap::new();
+        for cell in &cells {
+            if let Some(p) = parent(cell) {
+                groups.entry(p).or_default().push(cell.clone());
+            }
+        }
+        let full: Vec<(T, Vec<T>)> = groups.into_iter().filter(|(_, c)| c.len() == children).collect();
+        if full.is_empty() {
+            return cells.into_iter().collect();
+        }
+        for (p, c) in full {
+            for cell in c {
+                cells.remove(&cell);
+            }
+            cells.insert(p);
+        }
+    }
+}
+
+// 从start开始广度优先搜索满足accept的格子, 满足条件的格子必须是连通的
+fn flood<T: Ord + Clone>(start: T, neighbors: impl Fn(&T) -> Vec<T>, accept: impl Fn(&T) -> bool) -> Vec<T> {
+    let mut seen = BTreeSet::from([start.clone()]);
+    let mut queue = VecDeque::from([start]);
+    let mut res = Vec::new();
+    while let Some(cell) = queue.pop_front() {
+        for n in neighbors(&cell) {
+            if seen.insert(n.clone()) && accept(&n) {
+                queue.push_back(n);
+            }
+        }
+        res.push(cell);
+    }
+    res
+}
+
+// 格子的外接球冠与矩形相交时认为格子可能与矩形相交, 结果偏保守, 多出的格子由存储层的矩形条件排除
+fn may_intersect(rect: &Rect, vertices: &[Point]) -> bool {
+    let sum = vertices.iter().fold([0.0; 3], |s, v| [s[0] + v[0], s[1] + v[1], s[2] + v[2]]);
+    let center = normalize(sum);
+    let radius = vertices.iter().map(|v| angle(&center, v)).fold(0.0, f64::max);
+    rect.distance(&center) <= radius
+}
+
+// H3索引的第52~55位为分辨率, 之后每3位为一层的方向数字, 未使用的层为7.
+// 格子在更细的分辨率上的后代只有这些数字不同, 数字全取0和全取6分别得到索引最小和最大的后代
+fn h3_descendant(cell: i64, resolution: i32, digit: i64) -> i64 {
+    let parent = (cell >> 52) & 0xf;
+    let mut res = (cell & !(0xf << 52)) | (resolution as i64) << 52;
+    for r in parent + 1..=resolution as i64 {
+        let offset = (15 - r) * 3;
+        res = (res & !(7 << offset)) | digit << offset;
+    }
+    res
+}
+
+// 0层格子的平均面积(平方米), 每细一层面积约为上一层的1/7
+const H3_RES0_AREA: f64 = 4.357449416078383e12;
 
 #[derive(Debug, Clone)]
 pub(crate) struct H3Indexer {
-    resolution: i32,
+    resolutions: Vec<i32>,
+    max_cells: usize,
 }
 
 impl H3Indexer {
-    pub(crate) fn new(resolution: i32) -> Result<Self, Error> {
-        if resolution < 0 || resolution > 15 {
+    pub(crate) fn new(resolutions: Vec<i32>, max_cells: usize) -> Result<Self, Error> {
+        if let Some(resolution) = resolutions.iter().find(|r| !(0..=15).contains(*r)) {
             return Err(Error::msg(format!("invalid resolution for h3 indexer: {}", resolution)));
         }
-        Ok(Self { resolution })
+        if max_cells == 0 {
+            return Err(Error::msg("max cells for h3 indexer must be positive"));
+        }
+        Ok(Self {
+            resolutions: sorted_levels(resolutions)?,
+            max_cells,
+        })
     }
 }
 
 impl<'a> Indexer<'a, i64> for H3Indexer {
-    fn index(&self, latitude: f64, longitude: f64) -> i64 {
-        let coord = GeoCoord {
-            lat: unsafe { degsToRads(latitude) },
-            lon: unsafe { degsToRads(longitude) },
+    fn indices(&self, latitude: f64, longitude: f64) -> Vec<i64> {
+        self.resolutions.iter().map(|&r| h3::index(r, latitude, longitude)).collect()
+    }
+
+    fn level(&self, distance: f64) -> usize {
+        choose_level(self.resolutions.len(), self.max_cells, distance, |level| {
+            let area = H3_RES0_AREA / 7f64.powi(self.resolutions[level]);
+            // 正六边形的面积为3√3/2·边长², 直径为两倍边长
+            (area, 2.0 * (2.0 * area / (3.0 * 3f64.sqrt())).sqrt())
+        })
+    }
+
+    // 从地点所在的格子开始, 搜索边界与圆相交的格子, 圆是凸的, 这些格子一定是连通的
+    fn neighbors(&self, latitude: f64, longitude: f64, distance: f64, level: usize) -> Vec<i64> {
+        let p = lat_lng_to_point(latitude, longitude);
+        let radius = distance / EARTH_RADIUS;
+        let start = h3::index(self.resolutions[level], latitude, longitude);
+        // 地点不在相邻格子内, 到格子的距离就是到格子边界的距离
+        flood(start, |&cell| h3::k_ring(cell, 1), |&n| boundary_distance(&p, &h3::boundary(n)) <= radius)
+    }
+
+    fn polyfill(&self, rect: &Rect, level: usize) -> Vec<i64> {
+        let (latitude, longitude) = rect.center();
+        let start = h3::index(self.resolutions[level], latitude, longitude);
+        flood(start, |&cell| h3::k_ring(cell, 1), |&n| may_intersect(rect, &h3::boundary(n)))
+    }
+
+    fn compact(&self, cells: Vec<i64>) -> Vec<i64> {
+        h3::compact(&cells)
+    }
+
+    // H3的子格子并不完全落在父格子内, 只能按索引的层级关系匹配同一分辨率的后代
+    fn range(&self, cell: &i64, level: usize) -> RangeInclusive<i64> {
+        let resolution = self.resolutions[level];
+        h3_descendant(*cell, resolution, 0)..=h3_descendant(*cell, resolution, 6)
+    }
+}
+
+// 纯Rust实现, 索引为geohash字符串, 在Mongo中可以直接阅读
+#[derive(Debug, Clone)]
+pub(crate) struct GeohashIndexer {
+    precisions: Vec<usize>,
+    max_cells: usize,
+}
+
+// 格子的行数和列数, 总位数为奇数时经度多占一位
+fn geohash_grid(precision: usize) -> (i64, i64) {
+    let bits = precision as u32 * 5;
+    (1 << (bits / 2), 1 << (bits - bits / 2))
+}
+
+fn geohash_encode(latitude: f64, longitude: f64, precision: usize) -> String {
+    geohash::encode(Coord { x: longitude, y: latitude }, precision).unwrap()
+}
+
+// 与经纬度矩形相交的所有格子, west不大于east, 超出[-180, 180]的部分回绕
+fn geohash_cells(precision: usize, south: f64, north: f64, west: f64, east: f64) -> Vec<String> {
+    let (rows, cols) = geohash_grid(precision);
+    let (lat_step, lon_step) = (180.0 / rows as f64, 360.0 / cols as f64);
+    let row = |lat: f64| (((lat + 90.0) / lat_step).floor() as i64).min(rows - 1);
+    let col = |lon: f64| ((lon + 180.0) / lon_step).floor() as i64;
+    let mut res = Vec::new();
+    for i in row(south)..=row(north) {
+        let lat = -90.0 + (i as f64 + 0.5) * lat_step;
+        let (first, last) = (col(west), col(east));
+        // 跨越180度经线时取模回绕, 列数超过一圈时只取一圈
+        for j in first..=last.min(first + cols - 1) {
+            let lon = -180.0 + (j.rem_euclid(cols) as f64 + 0.5) * lon_step;
+            res.push(geohash_encode(lat, lon, precision));
+        }
+    }
+    res
+}
+
+impl GeohashIndexer {
+    pub(crate) fn new(precisions: Vec<usize>, max_cells: usize) -> Result<Self, Error> {
+        if let Some(precision) = precisions.iter().find(|p| !(1..=12).contains(*p)) {
+            return Err(Error::msg(format!("invalid precision for geohash indexer: {}", precision)));
+        }
+        if max_cells == 0 {
+            return Err(Error::msg("max cells for geohash indexer must be positive"));
+        }
+        Ok(Self {
+            precisions: sorted_levels(precisions)?,
+            max_cells,
+        })
+    }
+}
+
+impl<'a> Indexer<'a, String> for GeohashIndexer {
+    fn indices(&self, latitude: f64, longitude: f64) -> Vec<String> {
+        self.precisions.iter().map(|&p| geohash_encode(latitude, longitude, p)).collect()
+    }
+
+    // 按赤道附近的格子大小估计, 高纬度地区格子变窄, 实际的格子数会多一些
+    fn level(&self, distance: f64) -> usize {
+        choose_level(self.precisions.len(), self.max_cells, distance, |level| {
+            let (rows, cols) = geohash_grid(self.precisions[level]);
+            let degree = EARTH_RADIUS * PI / 180.0;
+            let (height, width) = (180.0 / rows as f64 * degree, 360.0 / cols as f64 * degree);
+            (height * width, height.hypot(width))
+        })
+    }
+
+    // 枚举与圆的外接经纬度矩形相交的所有格子
+    fn neighbors(&self, latitude: f64, longitude: f64, distance: f64, level: usize) -> Vec<String> {
+        let angle = distance / EARTH_RADIUS;
+        let south = (latitude - angle.to_degrees()).max(-90.0);
+        let north = (latitude + angle.to_degrees()).min(90.0);
+        // 纬度为φ、角半径为r的圆, 经度方向的最大跨度为asin(sin(r)/cos(φ)); 圆包含极点时覆盖所有经度
+        let cos = latitude.to_radians().cos();
+        let (west, east) = if angle.sin() < cos {
+            let span = (angle.sin() / cos).asin().to_degrees();
+            (longitude - span, longitude + span)
+        } else {
+            (-180.0, 180.0)
         };
-        (unsafe { geoToH3(&coord as *const GeoCoord, self.resolution) }) as i64
+        geohash_cells(self.precisions[level], south, north, west, east)
     }
 
-    fn neighbors(&self, index: i64, distance: f64) -> Vec<i64> {
-        let k = ((distance / 1000.0 - unsafe { edgeLengthKm(self.resolution) })
-            / unsafe { (edgeLengthKm(self.resolution)) * 2.0 })
-        .ceil() as i32;
-        let mut res = vec![0u64; unsafe { maxKringSize(k) } as usize];
-        unsafe {
-            kRing(index as u64, k, &mut res[0] as *mut H3Index);
+    // geohash格子本身就是经纬度矩形, 直接枚举
+    fn polyfill(&self, rect: &Rect, level: usize) -> Vec<String> {
+        geohash_cells(self.precisions[level], rect.south, rect.north, rect.west, rect.west + rect.width())
+    }
+
+    fn compact(&self, cells: Vec<String>) -> Vec<String> {
+        compact_by(cells, 32, |cell| (cell.len() > 1).then(|| cell[..cell.len() - 1].to_owned()))
+    }
+
+    // base32字母表按ASCII排序, 以cell为前缀的后代按字典序连续排列
+    fn range(&self, cell: &String, level: usize) -> RangeInclusive<String> {
+        let padding = self.precisions[level] - cell.len();
+        format!("{cell}{}", "0".repeat(padding))..=format!("{cell}{}", "z".repeat(padding))
+    }
+}
+
+// 纯Rust实现, 索引为S2格子id, 附近的格子由球冠覆盖得到, 比k环更贴合圆形的范围
+#[derive(Debug, Clone)]
+pub(crate) struct S2Indexer {
+    levels: Vec<u8>,
+    max_cells: usize,
+}
+
+// 各层格子对角线长度的上限为该值/2^level(弧度), 见s2geometry的kMaxDiag
+const S2_MAX_DIAG: f64 = 2.438654594434021;
+
+impl S2Indexer {
+    pub(crate) fn new(levels: Vec<u8>, max_cells: usize) -> Result<Self, Error> {
+        if let Some(level) = levels.iter().find(|&&l| l > s2::MAX_LEVEL) {
+            return Err(Error::msg(format!("invalid level for s2 indexer: {}", level)));
         }
-        res.into_iter().map(|v| v as i64).collect()
+        if max_cells == 0 {
+            return Err(Error::msg("max cells for s2 indexer must be positive"));
+        }
+        Ok(Self {
+            levels: sorted_levels(levels)?,
+            max_cells,
+        })
+    }
+}
+
+impl<'a> Indexer<'a, i64> for S2Indexer {
+    fn indices(&self, latitude: f64, longitude: f64) -> Vec<i64> {
+        let leaf = CellId::from_point(&lat_lng_to_point(latitude, longitude));
+        self.levels.iter().map(|&l| leaf.parent(l).0 as i64).collect()
+    }
+
+    fn level(&self, distance: f64) -> usize {
+        choose_level(self.levels.len(), self.max_cells, distance, |level| {
+            let scale = 2f64.powi(self.levels[level] as i32);
+            // 球面被6个面平分, 每细一层格子数为4倍
+            let area = 4.0 * PI * EARTH_RADIUS * EARTH_RADIUS / (6.0 * scale * scale);
+            (area, S2_MAX_DIAG / scale * EARTH_RADIUS)
+        })
+    }
+
+    fn neighbors(&self, latitude: f64, longitude: f64, distance: f64, level: usize) -> Vec<i64> {
+        s2::cover_cap(&lat_lng_to_point(latitude, longitude), distance / EARTH_RADIUS, self.levels[level])
+            .into_iter()
+            .map(|c| c.0 as i64)
+            .collect()
+    }
+
+    fn polyfill(&self, rect: &Rect, level: usize) -> Vec<i64> {
+        let (latitude, longitude) = rect.center();
+        let start = CellId::from_point(&lat_lng_to_point(latitude, longitude)).parent(self.levels[level]);
+        flood(start, |cell| cell.edge_neighbors().to_vec(), |n| may_intersect(rect, &n.vertices()))
+            .into_iter()
+            .map(|c| c.0 as i64)
+            .collect()
+    }
+
+    fn compact(&self, cells: Vec<i64>) -> Vec<i64> {
+        compact_by(cells, 4, |&cell| {
+            let cell = CellId(cell as u64);
+            (cell.level() > 0).then(|| cell.parent(cell.level() - 1).0 as i64)
+        })
+    }
+
+    // 同一个面上的格子id最高3位相同, 转为i64后大小关系不变
+    fn range(&self, cell: &i64, level: usize) -> RangeInclusive<i64> {
+        let (first, last) = CellId(*cell as u64).child_range(self.levels[level]);
+        first.0 as i64..=last.0 as i64
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::geo::haversine;
+
+    // 从起点沿方位角bearing(度)移动distance米后的位置
+    fn destination(latitude: f64, longitude: f64, bearing: f64, distance: f64) -> (f64, f64) {
+        let (lat, lon, bearing, angle) = (latitude.to_radians(), longitude.to_radians(), bearing.to_radians(), distance / EARTH_RADIUS);
+        let lat2 = (lat.sin() * angle.cos() + lat.cos() * angle.sin() * bearing.cos()).asin();
+        let lon2 = lon + (bearing.sin() * angle.sin() * lat.cos()).atan2(angle.cos() - lat.sin() * lat2.sin());
+        (lat2.to_degrees(), (lon2.to_degrees() + 540.0).rem_euclid(360.0) - 180.0)
+    }
+
+    // 包括靠近180度经线、极点、立方体顶点(S2)和五边形(H3)的位置
+    const CENTERS: [(f64, f64); 8] = [
+        (36.657004, 117.0242607),
+        (0.0, 179.999),
+        (-33.8688, -179.9999),
+        (35.26, -135.0),
+        (70.0, 20.0),
+        (89.999, 0.0),
+        (64.70000012793489, 10.53619907546767),
+        (-39.10000003802756, -122.30000078180956),
+    ];
+
+    // 沿各个方位对半径内的点采样, 在选中的层所在的格子都必须包含在neighbors中
+    fn assert_covers<'a, K, I>(indexer: &I, distance: f64)
+    where
+        K: std::fmt::Display + Send + Sync + PartialEq + 'a,
+        I: Indexer<'a, K>,
+    {
+        let level = indexer.level(distance);
+        for (lat, lon) in CENTERS {
+            let neighbors = indexer.neighbors(lat, lon, distance, level);
+            for bearing in (0..360).step_by(5) {
+                for ratio in [0.1, 0.3, 0.5, 0.7, 0.9, 0.99, 0.9999] {
+                    let (lat2, lon2) = destination(lat, lon, bearing as f64, distance * ratio);
+                    assert!(haversine(lat, lon, lat2, lon2) <= distance);
+                    let idx = indexer.indices(lat2, lon2).swap_remove(level);
+                    assert!(neighbors.contains(&idx), "{idx} not in neighbors of ({lat}, {lon})");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_geohash_index() {
+        let indexer = GeohashIndexer::new(vec![6], 100).unwrap();
+        assert_eq!(indexer.indices(36.657004, 117.0242607), vec!["wwe0wc"]);
+        assert!(GeohashIndexer::new(vec![0, 6], 100).is_err());
+        assert!(GeohashIndexer::new(vec![13], 100).is_err());
+    }
+
+    #[test]
+    fn test_geohash_neighbors() {
+        let indexer = GeohashIndexer::new(vec![6], 100).unwrap();
+        assert_covers(&indexer, 500.0);
+        assert_covers(&indexer, 5000.0);
+        // 中纬度地区500米只需要少量格子(格子约1.2公里x0.6公里)
+        let neighbors = indexer.neighbors(36.657004, 117.0242607, 500.0, 0);
+        assert!(neighbors.len() <= 12, "{}", neighbors.len());
+    }
+
     #[test]
     fn test_index() {
-        let indexer = H3Indexer::new(8).unwrap();
-        let idx = indexer.index(36.657004, 117.0242607);
+        let indexer = H3Indexer::new(vec![8], 100).unwrap();
+        let idx = h3::index(8, 36.657004, 117.0242607);
         println!("{:x}", idx);
-        let neighbors = indexer.neighbors(idx, 0.5);
+        let neighbors = indexer.neighbors(36.657004, 117.0242607, 500.0, 0);
+        assert!(neighbors.contains(&idx));
         for n in neighbors {
             println!("{:x}", n);
         }
     }
+
+    #[test]
+    fn test_h3_neighbors() {
+        let indexer = H3Indexer::new(vec![8], 100).unwrap();
+        assert_covers(&indexer, 500.0);
+        assert_covers(&indexer, 20000.0);
+        // 半径为0时只有所在的格子, 其余的格子都与圆相交
+        assert_eq!(indexer.neighbors(36.657004, 117.0242607, 0.0, 0), vec![h3::index(8, 36.657004, 117.0242607)]);
+        let p = lat_lng_to_point(36.657004, 117.0242607);
+        for n in indexer.neighbors(36.657004, 117.0242607, 20000.0, 0).into_iter().skip(1) {
+            assert!(boundary_distance(&p, &h3::boundary(n)) * EARTH_RADIUS <= 20000.0);
+        }
+    }
+
+    // 两种实现同时开启时, 对比纯Rust实现与C库的结果
+    #[cfg(all(feature = "h3-ffi", feature = "h3-pure"))]
+    #[test]
+    fn test_h3_pure_matches_ffi() {
+        for (lat, lon) in CENTERS {
+            for resolution in 0..=15 {
+                let idx = h3_ffi::index(resolution, lat, lon);
+                assert_eq!(h3_pure::index(resolution, lat, lon), idx, "({lat}, {lon}) at resolution {resolution}");
+                for k in 0..3 {
+                    let (mut ffi, mut pure) = (h3_ffi::k_ring(idx, k), h3_pure::k_ring(idx, k));
+                    ffi.sort();
+                    pure.sort();
+                    assert_eq!(pure, ffi, "k_ring({idx:x}, {k})");
+                }
+                let (mut ffi, mut pure) = (h3_ffi::compact(&h3_ffi::k_ring(idx, 2)), h3_pure::compact(&h3_pure::k_ring(idx, 2)));
+                ffi.sort();
+                pure.sort();
+                assert_eq!(pure, ffi, "compact of k_ring({idx:x}, 2)");
+                let (ffi, pure) = (h3_ffi::boundary(idx), h3_pure::boundary(idx));
+                assert_eq!(ffi.len(), pure.len());
+                // 跨越二十面体的面时插入的顶点计算方式略有不同, 误差在厘米以内
+                for (a, b) in ffi.iter().zip(&pure) {
+                    assert!(crate::geo::angle(a, b) < 1e-8, "boundary of {idx:x}: {}", crate::geo::angle(a, b));
+                }
+            }
+        }
+    }
+
+    // 选中的层覆盖圆时实际的格子数不超过上限, 更细一层则超过上限
+    fn assert_level<'a, K, I>(indexer: &I, distance: f64, max_cells: usize)
+    where
+        K: std::fmt::Display + Send + Sync + PartialEq + 'a,
+        I: Indexer<'a, K>,
+    {
+        let level = indexer.level(distance);
+        for (lat, lon) in CENTERS {
+            let count = indexer.neighbors(lat, lon, distance, level).len();
+            assert!(count <= max_cells, "{count} cells at level {level} for ({lat}, {lon})");
+        }
+        assert!(indexer.neighbors(36.657004, 117.0242607, distance, level + 1).len() > max_cells / 4);
+    }
+
+    #[test]
+    fn test_levels() {
+        let h3 = H3Indexer::new(vec![8, 6, 7, 5, 8], 100).unwrap();
+        assert!(H3Indexer::new(vec![], 100).is_err());
+        assert!(H3Indexer::new(vec![8], 0).is_err());
+        // 由粗到细
+        let indices = h3.indices(36.657004, 117.0242607);
+        assert_eq!(indices.len(), 4);
+        assert_eq!(indices[3], h3::index(8, 36.657004, 117.0242607));
+        assert_eq!(indices[1], h3::index(6, 36.657004, 117.0242607));
+        // 小半径使用最细的一层, 大半径使用较粗的层, 距离极大时使用最粗的一层
+        assert_eq!(h3.level(500.0), 3);
+        assert_eq!(h3.level(20000.0), 1);
+        assert_eq!(h3.level(1e7), 0);
+        assert_level(&h3, 20000.0, 100);
+        let s2 = S2Indexer::new(vec![9, 10, 11, 12, 13], 100).unwrap();
+        assert_eq!(s2.level(500.0), 4);
+        assert_level(&s2, 20000.0, 100);
+        let geohash = GeohashIndexer::new(vec![4, 5, 6], 100).unwrap();
+        assert_eq!(geohash.indices(36.657004, 117.0242607), vec!["wwe0", "wwe0w", "wwe0wc"]);
+        assert_eq!(geohash.level(500.0), 2);
+        assert_eq!(geohash.level(20000.0), 1);
+    }
+
+    // 合并后的格子在第level层的后代区间包含所有原始格子, 且后代的总数与原始格子数一致
+    fn assert_compact<'a, K, I>(indexer: &I, distance: f64, children: impl Fn(&K) -> u64) -> usize
+    where
+        K: std::fmt::Display + std::fmt::Debug + Send + Sync + Ord + Clone + 'a,
+        I: Indexer<'a, K>,
+    {
+        let cells = indexer.neighbors(36.657004, 117.0242607, distance, 0);
+        let compacted = indexer.compact(cells.clone());
+        assert_eq!(compacted.iter().map(children).sum::<u64>(), cells.len() as u64);
+        for cell in &cells {
+            assert_eq!(indexer.range(cell, 0), cell.clone()..=cell.clone());
+            assert!(compacted.iter().any(|c| indexer.range(c, 0).contains(cell)), "{cell} is not in any range");
+        }
+        compacted.len() * 10 / cells.len()
+    }
+
+    #[test]
+    fn test_compact() {
+        // 大半径下最细一层的格子大部分可以合并
+        let h3 = H3Indexer::new(vec![8], 100).unwrap();
+        assert!(assert_compact(&h3, 20000.0, |&c| 7u64.pow(8 - ((c >> 52) & 0xf) as u32)) < 5);
+        let s2 = S2Indexer::new(vec![13], 100).unwrap();
+        assert!(assert_compact(&s2, 20000.0, |&c| 4u64.pow((13 - CellId(c as u64).level()) as u32)) < 5);
+        let geohash = GeohashIndexer::new(vec![6], 100).unwrap();
+        assert!(assert_compact(&geohash, 20000.0, |c| 32u64.pow(6 - c.len() as u32)) < 5);
+        assert_eq!(geohash.range(&"wwe0".into(), 0), "wwe000".to_owned()..="wwe0zz".to_owned());
+        // 父格子的后代区间的端点也是同一分辨率的格子
+        let parent = h3::index(6, 36.657004, 117.0242607);
+        let range = h3.range(&parent, 0);
+        assert_eq!(h3::k_ring(*range.start(), 0), vec![*range.start()]);
+        assert_eq!((range.start() >> 52 & 0xf, range.end() >> 52 & 0xf), (8, 8));
+    }
+
+    // 在矩形内均匀采样, 所在的格子都必须包含在polyfill中
+    fn assert_polyfill<'a, K, I>(indexer: &I, rect: &Rect, level: usize) -> usize
+    where
+        K: std::fmt::Display + Send + Sync + PartialEq + 'a,
+        I: Indexer<'a, K>,
+    {
+        let cells = indexer.polyfill(rect, level);
+        for i in 0..=20 {
+            for j in 0..=20 {
+                let lat = rect.south + (rect.north - rect.south) * i as f64 / 20.0;
+                let lon = (rect.west + rect.width() * j as f64 / 20.0 + 540.0).rem_euclid(360.0) - 180.0;
+                let idx = indexer.indices(lat, lon).swap_remove(level);
+                assert!(cells.contains(&idx), "{idx} not in polyfill of {rect:?}");
+            }
+        }
+        cells.len()
+    }
+
+    #[test]
+    fn test_polyfill() {
+        let rects = [
+            Rect {
+                south: 36.6,
+                west: 117.0,
+                north: 36.7,
+                east: 117.1,
+            },
+            // 跨越180度经线
+            Rect {
+                south: -0.05,
+                west: 179.95,
+                north: 0.05,
+                east: -179.95,
+            },
+            Rect {
+                south: 64.65,
+                west: 10.45,
+                north: 64.75,
+                east: 10.65,
+            },
+        ];
+        let h3 = H3Indexer::new(vec![8], 100).unwrap();
+        let s2 = S2Indexer::new(vec![13], 100).unwrap();
+        let geohash = GeohashIndexer::new(vec![6], 100).unwrap();
+        for rect in &rects {
+            // 外接球冠的判断偏保守, 但格子数不会比矩形面积对应的格子数多太多
+            let count = assert_polyfill(&h3, rect, 0);
+            assert!((count as f64) < 2.0 * rect.area() / (H3_RES0_AREA / 7f64.powi(8)) + 50.0, "{count}");
+            assert_polyfill(&s2, rect, 0);
+            assert_polyfill(&geohash, rect, 0);
+        }
+        // geohash格子本身是矩形, 格子内的矩形只覆盖该格子的后代
+        let bbox = geohash::decode_bbox("wwe0").unwrap();
+        let rect = Rect {
+            south: bbox.min().y + 1e-9,
+            west: bbox.min().x + 1e-9,
+            north: bbox.max().y - 1e-9,
+            east: bbox.max().x - 1e-9,
+        };
+        let mut cells = geohash.polyfill(&rect, 0);
+        cells.sort();
+        cells.dedup();
+        assert_eq!(geohash.compact(cells), vec!["wwe0"]);
+    }
+
+    #[test]
+    fn test_s2_neighbors() {
+        let indexer = S2Indexer::new(vec![13], 100).unwrap();
+        assert!(S2Indexer::new(vec![31], 100).is_err());
+        assert_covers(&indexer, 500.0);
+        assert_covers(&indexer, 20000.0);
+        let neighbors = indexer.neighbors(36.657004, 117.0242607, 500.0, 0);
+        assert!(neighbors.len() <= 9, "{}", neighbors.len());
+    }
 }
@@ -1,69 +1,247 @@
+#![allow(clippy::type_complexity, clippy::too_many_arguments)]
+
 mod core;
 mod error;
+mod geo;
 mod handlers;
 mod indexers;
 mod models;
 mod mutexes;
 mod persisters;
+mod s2;
+mod validation;
 
 extern crate actix_header;
-use crate::core::Key;
+use crate::core::{Fenced, Indexer, Key, Mutex, UniquePersister};
 
-use crate::handlers::{add_location, nearby_locations};
+use crate::handlers::{
+    add_location, add_location_unique, bbox_locations, delete_location, get_location, json_error_handler, my_locations, nearby_locations, query_error_handler, update_location, update_location_unique,
+    BboxConfig, RadiusConfig,
+};
 use actix_web::{
     self,
-    web::{get, post, Data},
+    web::{delete, get, post, put, Data, JsonConfig, QueryConfig, ServiceConfig},
 };
 use anyhow::Error;
-use dotenv;
-use indexers::H3Indexer;
+use indexers::{GeohashIndexer, H3Indexer, S2Indexer};
 use log::warn;
-use mutexes::{RedisArg, RedisMutex};
-use persisters::MongoPersister;
+use mongodb::bson::doc;
+use mutexes::{LocalLock, LocalMutex, MyLock, RedisArg, RedisMutex};
+use persisters::{InMemoryPersister, MongoPersister};
+use std::collections::BTreeMap;
 use std::env;
 
 impl<'a> Key<'a> for i64 {}
 impl RedisArg for i64 {}
 impl RedisArg for String {}
 
+// 索引类型需要同时满足各个锁和存储实现的要求
+trait IndexKey: Key<'static> + RedisArg + 'static {}
+
+impl IndexKey for i64 {}
+impl IndexKey for String {}
+
 fn init_redis_mutex() -> Result<RedisMutex, Error> {
     let uris = env::var("REDIS_URIS")?.split(",").map(str::to_owned).collect();
     let expire = env::var("REDIS_EXPIRE").unwrap_or("60".into()).parse::<usize>()?;
     let timeout = env::var("REDIS_TIMEOUT").unwrap_or("10".into()).parse::<u64>()?;
-    let client = redlock::RedLock::new(uris);
-    Ok(RedisMutex::new(client, expire, timeout))
+    let watchdog = env::var("REDIS_WATCHDOG").unwrap_or("false".into()).parse::<bool>()?;
+    Ok(RedisMutex::new(uris, expire, timeout, watchdog)?)
+}
+
+fn init_local_mutex<K: Ord + Clone>() -> Result<LocalMutex<K>, Error> {
+    let timeout = env::var("LOCAL_MUTEX_TIMEOUT").unwrap_or("10".into()).parse::<u64>()?;
+    Ok(LocalMutex::new(timeout))
+}
+
+// 逗号分隔的"分类:半径"列表, 比如DUPLICATE_RADIUS_BY_CATEGORY=nursing-room:300,restroom:100
+fn env_radius_map(key: &str) -> Result<BTreeMap<String, f64>, Error> {
+    let mut map = BTreeMap::new();
+    for item in env::var(key).unwrap_or_default().split(",").map(str::trim).filter(|v| !v.is_empty()) {
+        let (category, radius) = item.split_once(":").ok_or_else(|| Error::msg(format!("invalid {key}: {item}")))?;
+        map.insert(category.trim().to_owned(), radius.trim().parse::<f64>()?);
+    }
+    Ok(map)
+}
+
+fn init_radius_config() -> Result<RadiusConfig, Error> {
+    let duplicate = env::var("DUPLICATE_RADIUS").unwrap_or("500".into()).parse::<f64>()?;
+    let search = env::var("SEARCH_RADIUS").unwrap_or("20000".into()).parse::<f64>()?;
+    let max_search = env::var("MAX_SEARCH_RADIUS").unwrap_or("50000".into()).parse::<f64>()?;
+    let duplicate_by_category = env_radius_map("DUPLICATE_RADIUS_BY_CATEGORY")?;
+    let search_by_category = env_radius_map("SEARCH_RADIUS_BY_CATEGORY")?;
+    if duplicate <= 0.0 || search <= 0.0 || search > max_search {
+        return Err(Error::msg(format!("invalid radius config: duplicate={duplicate}, search={search}, max_search={max_search}")));
+    }
+    if duplicate_by_category.values().any(|&r| r <= 0.0) || search_by_category.values().any(|&r| r <= 0.0 || r > max_search) {
+        return Err(Error::msg(format!(
+            "invalid radius config: duplicate_by_category={duplicate_by_category:?}, search_by_category={search_by_category:?}, max_search={max_search}"
+        )));
+    }
+    Ok(RadiusConfig {
+        duplicate,
+        search,
+        max_search,
+        duplicate_by_category,
+        search_by_category,
+    })
+}
+
+// 逗号分隔的列表, 比如H3_RESOLUTIONS=5,6,7,8
+fn env_list<T>(key: &str, default: &str) -> Result<Vec<T>, Error>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    Ok(env::var(key).unwrap_or(default.into()).split(",").map(|v| v.trim().parse()).collect::<Result<_, _>>()?)
+}
+
+fn init_max_cells() -> Result<usize, Error> {
+    Ok(env::var("INDEX_MAX_CELLS").unwrap_or("100".into()).parse::<usize>()?)
+}
+
+fn init_bbox_config() -> Result<BboxConfig, Error> {
+    let max_area = env::var("MAX_BBOX_AREA").unwrap_or("10000".into()).parse::<f64>()?;
+    if max_area <= 0.0 {
+        return Err(Error::msg(format!("invalid bbox config: max_area={max_area}")));
+    }
+    Ok(BboxConfig { max_area: max_area * 1e6 })
+}
+
+fn init_h3_indexer() -> Result<H3Indexer, Error> {
+    H3Indexer::new(env_list("H3_RESOLUTIONS", "5,6,7,8")?, init_max_cells()?)
+}
+
+fn init_geohash_indexer() -> Result<GeohashIndexer, Error> {
+    GeohashIndexer::new(env_list("GEOHASH_PRECISIONS", "4,5,6")?, init_max_cells()?)
+}
+
+fn init_s2_indexer() -> Result<S2Indexer, Error> {
+    S2Indexer::new(env_list("S2_LEVELS", "9,10,11,12,13")?, init_max_cells()?)
 }
 
 async fn init_mongo_persister() -> Result<MongoPersister, Error> {
     let uris = env::var("MONGO_URIS")?;
     let database = env::var("MONGO_DATABASE")?;
-    let db = mongodb::Client::with_options(mongodb::options::ClientOptions::parse(uris).await?)?.database(&database);
-    Ok(MongoPersister::new(db))
+    let client = mongodb::Client::with_options(mongodb::options::ClientOptions::parse(uris).await?)?;
+    // 两种一致性模式的写入都使用事务, 单机部署时启动失败, 而不是每次写入时才报错
+    let hello = client.database("admin").run_command(doc! {"isMaster": 1}, None).await?;
+    if !hello.contains_key("setName") && hello.get_str("msg").ok() != Some("isdbgrid") {
+        return Err(Error::msg("MongoDB must be a replica set or a sharded cluster, writes use transactions"));
+    }
+    Ok(MongoPersister::new(client, &database))
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
-    if let Err(e) = dotenv::dotenv() {
-        if !e.not_found() {
-            panic!("{e}");
-        }
-        warn!("cannot load .env: {e}");
-    }
-    let mutex = init_redis_mutex().expect("failed to init redis mutex");
-    let indexer = H3Indexer::new(8).unwrap();
-    let persister = init_mongo_persister().await.expect("failed to init mongo persister");
+// 与一致性模式无关的路由和共享数据
+fn configure<K, I, P>(cfg: &mut ServiceConfig, indexer: I, persister: P, radius: RadiusConfig, bbox: BboxConfig)
+where
+    K: IndexKey,
+    I: Indexer<'static, K> + Clone + Send + 'static,
+    P: UniquePersister<K> + Clone + Send + 'static,
+{
+    cfg.route("/locations", get().to(nearby_locations::<K, I, P>))
+        .route("/locations/bbox", get().to(bbox_locations::<K, I, P>))
+        .route("/locations/{id}", get().to(get_location::<K, P>))
+        .route("/locations/{id}", delete().to(delete_location::<K, P>))
+        .route("/users/me/locations", get().to(my_locations::<K, P>))
+        .app_data(JsonConfig::default().error_handler(json_error_handler))
+        .app_data(QueryConfig::default().error_handler(query_error_handler))
+        .app_data(Data::new(indexer))
+        .app_data(Data::new(persister))
+        .app_data(Data::new(radius))
+        .app_data(Data::new(bbox));
+}
+
+async fn serve<K, I, M, L, P>(indexer: I, mutex: M, persister: P) -> std::io::Result<()>
+where
+    K: IndexKey,
+    I: Indexer<'static, K> + Clone + Send + 'static,
+    M: Mutex<K, L> + Clone + Send + 'static,
+    L: Fenced + Send + 'static,
+    P: UniquePersister<K> + Clone + Send + 'static,
+{
+    let radius = init_radius_config().expect("failed to init radius config");
+    let bbox = init_bbox_config().expect("failed to init bbox config");
     let port = env::var("PORT").unwrap_or("8000".into());
     actix_web::HttpServer::new(move || {
         actix_web::App::new()
-            .route("/locations", post().to(add_location::<i64, H3Indexer, RedisMutex, MongoPersister, mutexes::MyLock>))
-            .route("/locations", get().to(nearby_locations::<i64, H3Indexer, MongoPersister>))
+            .route("/locations", post().to(add_location::<K, I, M, P, L>))
+            .route("/locations", put().to(update_location::<K, I, M, P, L>))
             .app_data(Data::new(mutex.clone()))
-            .app_data(Data::new(indexer.clone()))
-            .app_data(Data::new(persister.clone()))
+            .configure(|cfg| configure(cfg, indexer.clone(), persister.clone(), radius.clone(), bbox.clone()))
     })
     .bind(format!("0.0.0.0:{port}"))
     .expect("failed to bind address")
     .run()
     .await
 }
+
+// 由存储层的事务保证附近不重复, 不需要分布式锁
+async fn serve_unique<K, I, P>(indexer: I, persister: P) -> std::io::Result<()>
+where
+    K: IndexKey,
+    I: Indexer<'static, K> + Clone + Send + 'static,
+    P: UniquePersister<K> + Clone + Send + 'static,
+{
+    let radius = init_radius_config().expect("failed to init radius config");
+    let bbox = init_bbox_config().expect("failed to init bbox config");
+    let port = env::var("PORT").unwrap_or("8000".into());
+    actix_web::HttpServer::new(move || {
+        actix_web::App::new()
+            .route("/locations", post().to(add_location_unique::<K, I, P>))
+            .route("/locations", put().to(update_location_unique::<K, I, P>))
+            .configure(|cfg| configure(cfg, indexer.clone(), persister.clone(), radius.clone(), bbox.clone()))
+    })
+    .bind(format!("0.0.0.0:{port}"))
+    .expect("failed to bind address")
+    .run()
+    .await
+}
+
+async fn start<K, I, P>(indexer: I, persister: P) -> std::io::Result<()>
+where
+    K: IndexKey,
+    I: Indexer<'static, K> + Clone + Send + 'static,
+    P: UniquePersister<K> + Clone + Send + 'static,
+{
+    match env::var("CONSISTENCY").unwrap_or("lock".into()).as_str() {
+        "lock" => {}
+        "transaction" => return serve_unique(indexer, persister).await,
+        c => panic!("unknown consistency mode: {c}"),
+    }
+    match env::var("MUTEX").unwrap_or("redis".into()).as_str() {
+        "redis" => serve::<K, _, _, MyLock, _>(indexer, init_redis_mutex().expect("failed to init redis mutex"), persister).await,
+        "local" => serve::<K, _, _, LocalLock<K>, _>(indexer, init_local_mutex().expect("failed to init local mutex"), persister).await,
+        m => panic!("unknown mutex: {m}"),
+    }
+}
+
+async fn run<K, I>(indexer: I) -> std::io::Result<()>
+where
+    K: IndexKey,
+    I: Indexer<'static, K> + Clone + Send + 'static,
+    MongoPersister: UniquePersister<K>,
+{
+    match env::var("PERSISTER").unwrap_or("mongo".into()).as_str() {
+        "mongo" => start(indexer, init_mongo_persister().await.expect("failed to init mongo persister")).await,
+        "memory" => start(indexer, InMemoryPersister::<K>::new()).await,
+        p => panic!("unknown persister: {p}"),
+    }
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
+    if let Err(e) = dotenv::dotenv() {
+        if !e.not_found() {
+            panic!("{e}");
+        }
+        warn!("cannot load .env: {e}");
+    }
+    match env::var("INDEXER").unwrap_or("h3".into()).as_str() {
+        "h3" => run(init_h3_indexer().expect("failed to init h3 indexer")).await,
+        "geohash" => run(init_geohash_indexer().expect("failed to init geohash indexer")).await,
+        "s2" => run(init_s2_indexer().expect("failed to init s2 indexer")).await,
+        i => panic!("unknown indexer: {i}"),
+    }
+}
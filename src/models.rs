@@ -1,17 +1,36 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Location<I> {
     pub id: String,
     pub latitude: f64,
     pub longitude: f64,
     pub geo_index: I,
+    // 地点在各层所在的格子, 由粗到细, 最后一个即geo_index
+    pub geo_indices: Vec<I>,
+    pub uid: String,
+    #[serde(flatten)]
+    pub attributes: LocationAttributes,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct LocationAttributes {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // 任意的JSON对象, 由客户端自行解释
+    #[serde(default)]
+    pub properties: Option<serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct LocationQuery<I> {
-    geo_index: I,
-    get_indices: Vec<I>,
+pub struct LocationWithDistance<I> {
+    #[serde(flatten)]
+    pub location: Location<I>,
+    pub distance: f64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -19,5 +38,39 @@ pub struct LocationCommand<I> {
     pub latitude: f64,
     pub longitude: f64,
     pub geo_index: I,
+    pub geo_indices: Vec<I>,
     pub uid: String,
+    pub attributes: LocationAttributes,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LocationUpdate<I> {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub geo_index: I,
+    pub geo_indices: Vec<I>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagsMatch {
+    // 包含任意一个标签即可
+    #[default]
+    Any,
+    // 必须包含所有标签
+    All,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct LocationFilter {
+    pub category: Option<String>,
+    pub tags: Vec<String>,
+    pub tags_match: TagsMatch,
+}
+
+// 加锁时得到的fencing token以及加锁的格子, 写入时据此拒绝锁已过期的写入者
+#[derive(Debug, Clone)]
+pub struct Fence<I> {
+    pub token: u64,
+    pub cells: Vec<I>,
 }
@@ -1,47 +1,161 @@
-use crate::core::Mutex;
-use anyhow::Error;
-use log::error;
-use redis::{self, ToRedisArgs};
-use redlock::{Lock, RedLock};
+use crate::core::{Fenced, Mutex};
+use crate::error::Error;
+use futures::future::join_all;
+use log::warn;
+use rand::Rng;
+use redis::aio::ConnectionManager;
+use redis::{self, Script, ToRedisArgs};
+use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::future::Future;
 use std::pin::Pin;
-use tokio::time::{timeout, Duration};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{OnceCell, OwnedMutexGuard};
+use tokio::time::{sleep, timeout, Duration, Instant};
 
+// 重试间隔的初始值和上限(毫秒)
+const BACKOFF_BASE: u64 = 10;
+const BACKOFF_MAX: u64 = 500;
+
+// fencing token计数器的key
+const TOKEN_KEY: &str = "fencing-token";
+
+// 所有key都未被占用时才一次性全部加锁并返回递增后的fencing token, 否则一个都不加, 返回0
+// KEYS[1]为token计数器, 其余为要加锁的key, ARGV[1]为锁的值, ARGV[2]为有效时长(毫秒), ARGV[3]为token的下限
+const LOCK_SCRIPT: &str = r#"
+for i = 2, #KEYS do
+    if redis.call("exists", KEYS[i]) == 1 then
+        return 0
+    end
+end
+for i = 2, #KEYS do
+    redis.call("set", KEYS[i], ARGV[1], "PX", ARGV[2])
+end
+local token = redis.call("incr", KEYS[1])
+if token < tonumber(ARGV[3]) then
+    token = tonumber(ARGV[3])
+    redis.call("set", KEYS[1], ARGV[3])
+end
+return token
+"#;
+
+// 把token计数器推进到不小于ARGV[1], KEYS[1]为token计数器
+const SYNC_TOKEN_SCRIPT: &str = r#"
+local current = tonumber(redis.call("get", KEYS[1]) or "0")
+if current < tonumber(ARGV[1]) then
+    redis.call("set", KEYS[1], ARGV[1])
+end
+return 1
+"#;
+
+// 所有key仍由自己持有时才全部延长有效时长, ARGV[1]为锁的值, ARGV[2]为新的有效时长(毫秒)
+const EXTEND_SCRIPT: &str = r#"
+for i = 1, #KEYS do
+    if redis.call("get", KEYS[i]) ~= ARGV[1] then
+        return 0
+    end
+end
+for i = 1, #KEYS do
+    redis.call("pexpire", KEYS[i], ARGV[2])
+end
+return 1
+"#;
+
+// 只删除自己持有的锁
+const UNLOCK_SCRIPT: &str = r#"
+local n = 0
+for i = 1, #KEYS do
+    if redis.call("get", KEYS[i]) == ARGV[1] then
+        n = n + redis.call("del", KEYS[i])
+    end
+end
+return n
+"#;
+
+// 当前时间(微秒)作为fencing token的下限, 计数器丢失或进程重启后发放的token仍大于已经写入存储的token
+// 要求各节点的时钟基本同步, 且每秒发放的token不超过一百万个
+fn clock_token() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64)
+}
+
+// 一把锁对应一组key, 这组key是同时加锁同时释放的
 pub struct MyLock {
-    pub resource: Vec<u8>,
+    pub resources: Vec<Vec<u8>>,
     pub val: Vec<u8>,
+    // 从validity_start起算的有效时长(毫秒), 延长后更新
     pub validity_time: usize,
+    pub validity_start: Instant,
+    pub token: u64,
 }
 
-impl From<Lock<'_>> for MyLock {
-    fn from(l: Lock) -> Self {
-        Self {
-            resource: l.resource,
-            val: l.val,
-            validity_time: l.validity_time,
-        }
+impl Fenced for MyLock {
+    fn token(&self) -> u64 {
+        self.token
+    }
+
+    fn expires_at(&self) -> Option<Instant> {
+        Some(self.validity_start + Duration::from_millis(self.validity_time as u64))
     }
 }
 
-impl MyLock {
-    fn to_lock(self, rl: &RedLock) -> Lock {
-        Lock {
-            resource: self.resource,
-            val: self.val,
-            validity_time: self.validity_time,
-            lock_manager: rl,
-        }
+#[derive(Clone)]
+struct RedisNode {
+    client: redis::Client,
+    // 第一次使用时才建立连接, 之后由ConnectionManager负责断线重连
+    conn: Arc<OnceCell<ConnectionManager>>,
+}
+
+impl RedisNode {
+    async fn connection(&self) -> Result<ConnectionManager, Error> {
+        let conn = self.conn.get_or_try_init(|| ConnectionManager::new(self.client.clone())).await?;
+        Ok(conn.clone())
+    }
+
+    // 加锁成功时返回fencing token
+    async fn lock(&self, resources: &[Vec<u8>], val: &[u8], expire: usize) -> Result<Option<u64>, Error> {
+        let mut conn = self.connection().await?;
+        let res: u64 = Script::new(LOCK_SCRIPT)
+            .key(TOKEN_KEY)
+            .key(resources)
+            .arg(val)
+            .arg(expire)
+            .arg(clock_token())
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(Some(res).filter(|&t| t > 0))
+    }
+
+    async fn sync_token(&self, token: u64) -> Result<(), Error> {
+        let mut conn = self.connection().await?;
+        Script::new(SYNC_TOKEN_SCRIPT).key(TOKEN_KEY).arg(token).invoke_async::<_, i32>(&mut conn).await?;
+        Ok(())
+    }
+
+    async fn extend(&self, resources: &[Vec<u8>], val: &[u8], expire: usize) -> Result<bool, Error> {
+        let mut conn = self.connection().await?;
+        let res: i32 = Script::new(EXTEND_SCRIPT).key(resources).arg(val).arg(expire).invoke_async(&mut conn).await?;
+        Ok(res == 1)
+    }
+
+    async fn unlock(&self, resources: &[Vec<u8>], val: &[u8]) -> Result<(), Error> {
+        let mut conn = self.connection().await?;
+        Script::new(UNLOCK_SCRIPT).key(resources).arg(val).invoke_async::<_, i32>(&mut conn).await?;
+        Ok(())
     }
 }
 
+// Redlock算法: 在超过半数的节点上加锁成功且剩余有效时长大于0才视为获取成功
 #[derive(Clone)]
 pub(crate) struct RedisMutex {
-    client: RedLock,
-    // 有效时长， 超过此时长视为已获取锁的线程超时未释放锁或者此锁在此有效时长内没有被获取
+    nodes: Vec<RedisNode>,
+    // 有效时长(秒)， 超过此时长视为已获取锁的线程超时未释放锁或者此锁在此有效时长内没有被获取
     expire: usize,
-    // 获取锁的等待时长
+    // 获取锁的等待时长(秒)
     timeout: u64,
+    // 是否在持有锁期间自动延长锁
+    watchdog: bool,
 }
 
 pub(crate) trait RedisArg: ToRedisArgs + Display + Send + Sync {}
@@ -50,66 +164,364 @@ impl RedisArg for u64 {}
 
 impl<T: RedisArg> RedisArg for &T {}
 
+// 带随机抖动的指数退避, 避免多个竞争者同时重试
+pub(crate) fn backoff(attempt: u32) -> Duration {
+    let max = BACKOFF_BASE.saturating_mul(1 << attempt.min(16)).min(BACKOFF_MAX);
+    Duration::from_millis(rand::thread_rng().gen_range(0..=max))
+}
+
 impl RedisMutex {
-    pub fn new(client: RedLock, expire: usize, timeout: u64) -> Self {
-        Self { client, expire, timeout }
+    pub fn new(uris: Vec<String>, expire: usize, timeout: u64, watchdog: bool) -> Result<Self, Error> {
+        let nodes = uris
+            .into_iter()
+            .map(|uri| {
+                Ok(RedisNode {
+                    client: redis::Client::open(uri)?,
+                    conn: Arc::new(OnceCell::new()),
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(Self { nodes, expire, timeout, watchdog })
+    }
+
+    fn quorum(&self) -> usize {
+        self.nodes.len() / 2 + 1
     }
-    async fn acquire<K: RedisArg>(self, key: &K) -> Result<MyLock, Error> {
+
+    // 时钟漂移按有效时长的1%加2毫秒估算
+    fn drift(expire: usize) -> usize {
+        expire / 100 + 2
+    }
+
+    // 与加锁相同, 在超过半数的节点上延长成功且剩余有效时长大于0才视为成功
+    // 锁已经过期时不再延长, 期间可能已经有别人获取过这些key
+    async fn extend(&self, lock: &mut MyLock) -> Result<(), Error> {
+        let expire = self.expire * 1000;
+        let start = Instant::now();
+        if lock.expires_at().is_some_and(|t| t <= start) {
+            return Err(Error::LockExpired);
+        }
+        let results = join_all(self.nodes.iter().map(|n| n.extend(&lock.resources, &lock.val, expire))).await;
+        let mut n = 0;
+        for res in results {
+            match res {
+                Ok(true) => n += 1,
+                Ok(false) => {}
+                Err(e) => warn!("failed to extend lock on redis node: {e}"),
+            }
+        }
+        let drift = Self::drift(expire);
+        let now = Instant::now();
+        let elapsed = (now - start).as_millis() as usize;
+        if n >= self.quorum() && expire > elapsed + drift {
+            lock.validity_time = expire - elapsed - drift;
+            lock.validity_start = now;
+            return Ok(());
+        }
+        Err(Error::LockExpired)
+    }
+
+    async fn try_lock(&self, resources: &[Vec<u8>], val: &[u8]) -> Option<MyLock> {
+        let expire = self.expire * 1000;
+        let start = Instant::now();
+        let results = join_all(self.nodes.iter().map(|n| n.lock(resources, val, expire))).await;
+        let mut n = 0;
+        let mut token = 0;
+        for res in results {
+            match res {
+                Ok(Some(t)) => {
+                    n += 1;
+                    token = token.max(t);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("failed to lock on redis node: {e}"),
+            }
+        }
+        // 各节点的计数器相互独立, 多数派上的最大值本身不是全局递增的. 加锁成功后再把超过半数节点的计数器推进到token,
+        // 之后的持有者加锁的多数派与这些节点至少有一个交集, 在该节点上递增得到的token一定更大
+        if n >= self.quorum() {
+            n = 0;
+            for res in join_all(self.nodes.iter().map(|n| n.sync_token(token))).await {
+                match res {
+                    Ok(()) => n += 1,
+                    Err(e) => warn!("failed to sync fencing token on redis node: {e}"),
+                }
+            }
+        }
+        let drift = Self::drift(expire);
+        let now = Instant::now();
+        let elapsed = (now - start).as_millis() as usize;
+        if n >= self.quorum() && expire > elapsed + drift {
+            return Some(MyLock {
+                resources: resources.to_vec(),
+                val: val.to_vec(),
+                validity_time: expire - elapsed - drift,
+                validity_start: now,
+                token,
+            });
+        }
+        self.unlock(resources, val).await;
+        None
+    }
+
+    async fn unlock(&self, resources: &[Vec<u8>], val: &[u8]) {
+        for res in join_all(self.nodes.iter().map(|n| n.unlock(resources, val))).await {
+            if let Err(e) = res {
+                warn!("failed to unlock on redis node: {e}");
+            }
+        }
+    }
+
+    // 每次尝试都是在各节点上原子地获取整组key, 失败后退避重试, 直到超时
+    async fn acquire<K: RedisArg>(&self, keys: &[K]) -> Result<MyLock, Error> {
+        let mut resources: Vec<Vec<u8>> = keys.iter().map(|k| format!("{}", k).into_bytes()).collect();
+        resources.sort();
+        resources.dedup();
+        let val = hex::encode(rand::thread_rng().gen::<[u8; 20]>()).into_bytes();
         let res = timeout(Duration::from_secs(self.timeout), async {
+            let mut attempt = 0;
             loop {
-                if let Some(l) = self.client.lock(format!("{}", key).as_bytes(), self.expire) {
+                if let Some(l) = self.try_lock(&resources, &val).await {
                     return l;
                 }
+                sleep(backoff(attempt)).await;
+                attempt += 1;
             }
         })
-        .await?;
-        Ok(res.into())
+        .await;
+        match res {
+            Ok(l) => Ok(l),
+            Err(_) => {
+                // 超时时可能有请求已经在部分节点上加锁成功, 需要清理掉
+                self.unlock(&resources, &val).await;
+                Err(Error::LockTimeout)
+            }
+        }
+    }
+
+    async fn release(&self, lock: MyLock) {
+        self.unlock(&lock.resources, &lock.val).await;
     }
 }
 
 impl<K: RedisArg + 'static> Mutex<K, MyLock> for RedisMutex {
-    fn multiple_acquire(self, keys: Vec<K>) -> Pin<Box<dyn Future<Output = Result<Vec<MyLock>, Error>>>> {
+    fn multiple_acquire(self, keys: Vec<K>) -> Pin<Box<dyn Future<Output = Result<Vec<MyLock>, Error>> + Send>> {
         Box::pin(async move {
-            let mut locks = Vec::new();
-            for key in &keys {
-                match self.clone().acquire(key).await {
-                    Ok(l) => locks.push(l),
-                    Err(e) => {
-                        error!("{e}");
-                        break;
-                    }
-                }
-            }
-            if locks.len() != keys.len() {
-                for l in locks {
-                    self.client.clone().unlock(&l.to_lock(&self.client));
-                }
-                return Err(Error::msg("failed to get lock"));
-            }
-            Ok(locks)
+            let lock = self.acquire(&keys).await?;
+            Ok(vec![lock])
         })
     }
 
-    fn multiple_release(self, locks: Vec<MyLock>) -> Pin<Box<dyn Future<Output = Result<(), Error>>>> {
+    fn multiple_release(self, locks: Vec<MyLock>) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> {
         Box::pin(async move {
             for lock in locks {
-                self.client.unlock(&lock.to_lock(&self.client));
+                self.release(lock).await;
             }
             Ok(())
         })
     }
 
-    fn single_acquire(self, key: K) -> Pin<Box<dyn Future<Output = Result<MyLock, Error>>>> {
+    fn single_acquire(self, key: K) -> Pin<Box<dyn Future<Output = Result<MyLock, Error>> + Send>> {
         Box::pin(async move {
-            let lock = self.acquire(&key).await?;
+            let lock = self.acquire(&[key]).await?;
             Ok(lock)
         })
     }
 
-    fn single_release(self, lock: MyLock) -> Pin<Box<dyn Future<Output = Result<(), Error>>>> {
+    fn single_release(self, lock: MyLock) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> {
+        Box::pin(async move {
+            self.release(lock).await;
+            Ok(())
+        })
+    }
+
+    fn extend<'a>(self, locks: &'a mut [MyLock]) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>> {
         Box::pin(async move {
-            self.client.unlock(&lock.to_lock(&self.client));
+            for lock in locks {
+                RedisMutex::extend(&self, lock).await?;
+            }
             Ok(())
         })
     }
+
+    fn watchdog(&self) -> bool {
+        self.watchdog
+    }
+}
+
+pub struct LocalLock<K> {
+    key: K,
+    guard: OwnedMutexGuard<()>,
+    token: u64,
+}
+
+impl<K> Fenced for LocalLock<K> {
+    fn token(&self) -> u64 {
+        self.token
+    }
+}
+
+// 进程内的互斥锁, 每个key对应一个异步锁, 只适用于单节点部署和测试
+#[derive(Clone)]
+pub(crate) struct LocalMutex<K> {
+    table: Arc<std::sync::Mutex<BTreeMap<K, Arc<tokio::sync::Mutex<()>>>>>,
+    // 从启动时的时间(微秒)开始, 每次加锁成功后递增, 重启后不会小于之前写入存储的token
+    token: Arc<AtomicU64>,
+    // 获取锁的等待时长
+    timeout: u64,
+}
+
+impl<K: Ord + Clone> LocalMutex<K> {
+    pub fn new(timeout: u64) -> Self {
+        Self {
+            table: Arc::new(std::sync::Mutex::new(BTreeMap::new())),
+            token: Arc::new(AtomicU64::new(clock_token())),
+            timeout,
+        }
+    }
+
+    // 与RedisMutex相同, 整组key共用一个等待时长, 超时后释放已经获取的锁
+    async fn acquire(&self, keys: Vec<K>) -> Result<Vec<LocalLock<K>>, Error> {
+        let (mut locks, pending) = (Vec::new(), keys.clone());
+        let acquired = &mut locks;
+        let res = timeout(Duration::from_secs(self.timeout), async move {
+            for key in pending {
+                let m = self.table.lock().unwrap().entry(key.clone()).or_default().clone();
+                let guard = m.lock_owned().await;
+                acquired.push(LocalLock {
+                    key,
+                    guard,
+                    token: self.token.fetch_add(1, Ordering::SeqCst) + 1,
+                });
+            }
+        })
+        .await;
+        if res.is_err() {
+            for l in locks {
+                self.release(l);
+            }
+            // 正在等待的key没有持有者时也从表中移除
+            for key in &keys {
+                self.cleanup(key);
+            }
+            return Err(Error::LockTimeout);
+        }
+        Ok(locks)
+    }
+
+    fn release(&self, lock: LocalLock<K>) {
+        drop(lock.guard);
+        self.cleanup(&lock.key);
+    }
+
+    // 没有任何持有者和等待者时从表中移除, 避免表无限增长
+    fn cleanup(&self, key: &K) {
+        let mut table = self.table.lock().unwrap();
+        if let Some(m) = table.get(key) {
+            if Arc::strong_count(m) == 1 {
+                table.remove(key);
+            }
+        }
+    }
+}
+
+impl<K: Ord + Clone + Send + 'static> Mutex<K, LocalLock<K>> for LocalMutex<K> {
+    fn multiple_acquire(self, keys: Vec<K>) -> Pin<Box<dyn Future<Output = Result<Vec<LocalLock<K>>, Error>> + Send>> {
+        Box::pin(async move { self.acquire(keys).await })
+    }
+
+    fn multiple_release(self, locks: Vec<LocalLock<K>>) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> {
+        Box::pin(async move {
+            for lock in locks {
+                self.release(lock);
+            }
+            Ok(())
+        })
+    }
+
+    fn single_acquire(self, key: K) -> Pin<Box<dyn Future<Output = Result<LocalLock<K>, Error>> + Send>> {
+        Box::pin(async move { self.acquire(vec![key]).await.map(|mut locks| locks.remove(0)) })
+    }
+
+    fn single_release(self, lock: LocalLock<K>) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> {
+        Box::pin(async move {
+            self.release(lock);
+            Ok(())
+        })
+    }
+
+    // 进程内的锁不会过期
+    fn extend<'a>(self, _: &'a mut [LocalLock<K>]) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        for attempt in 0..32 {
+            let max = Duration::from_millis((BACKOFF_BASE << attempt.min(16)).min(BACKOFF_MAX));
+            assert!(backoff(attempt) <= max);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_local_mutex() {
+        let mutex = LocalMutex::<i64>::new(10);
+        let locks = mutex.clone().multiple_acquire(vec![1, 2, 3]).await.unwrap();
+        // 与已持有的锁有交集时, 等待超时后失败, 且不会占用其他key
+        match mutex.clone().multiple_acquire(vec![3, 4]).await {
+            Err(Error::LockTimeout) => {}
+            _ => panic!("expected lock timeout"),
+        }
+        let other = mutex.clone().single_acquire(4).await.unwrap();
+        let token = other.token();
+        assert!(locks.iter().all(|l| l.token() < token));
+        mutex.clone().single_release(other).await.unwrap();
+        // 释放后等待者才能获取到锁
+        let waiter = mutex.clone().multiple_acquire(vec![2, 4]);
+        let release = async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            mutex.clone().multiple_release(locks).await.unwrap();
+        };
+        let (locks, _) = tokio::join!(waiter, release);
+        let locks = locks.unwrap();
+        assert!(locks.iter().all(|l| l.token() > token));
+        mutex.clone().multiple_release(locks).await.unwrap();
+        assert!(mutex.table.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_local_mutex_timeout() {
+        let mutex = LocalMutex::<i64>::new(10);
+        let first = mutex.clone().single_acquire(2).await.unwrap();
+        let second = mutex.clone().single_acquire(3).await.unwrap();
+        // 整组key只等待一个超时时长, 而不是每个key各等待一次
+        let start = Instant::now();
+        match mutex.clone().multiple_acquire(vec![1, 2, 3]).await {
+            Err(Error::LockTimeout) => {}
+            _ => panic!("expected lock timeout"),
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        // 超时前已经获取的锁被释放
+        let lock = mutex.clone().single_acquire(1).await.unwrap();
+        mutex.clone().multiple_release(vec![first, second, lock]).await.unwrap();
+        assert!(mutex.table.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_local_mutex_token_after_restart() {
+        let mutex = LocalMutex::<i64>::new(10);
+        let locks = mutex.clone().multiple_acquire(vec![1, 2, 3]).await.unwrap();
+        let token = locks.iter().map(Fenced::token).max().unwrap();
+        mutex.multiple_release(locks).await.unwrap();
+        // 重启后计数器从当前时间开始, 发放的token大于重启前的token
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let restarted = LocalMutex::<i64>::new(10);
+        let lock = restarted.clone().single_acquire(1).await.unwrap();
+        assert!(lock.token() > token);
+    }
 }
@@ -1,16 +1,26 @@
-use crate::core::Persister;
+use crate::core::{Persister, UniquePersister};
+use crate::error::Error;
+use crate::geo::{haversine, Rect, EARTH_RADIUS};
 use crate::models::*;
+use crate::mutexes::backoff;
+use crate::validation::FieldError;
 use futures::{StreamExt, TryStreamExt};
 use mongodb::{
-    bson::{doc, from_document, oid::ObjectId, Bson, Document},
-    options::FindOptions,
-    Cursor,
+    bson::{doc, from_document, oid::ObjectId, to_bson, Bson, Document},
+    error::{TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT},
+    options::{Acknowledgment, FindOptions, ReadConcern, TransactionOptions, UpdateOptions, WriteConcern},
+    ClientSession, Cursor,
 };
+use tokio::time::{sleep, timeout_at, Duration, Instant};
 
 use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, RwLock};
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct GeoJSON {
+    #[allow(dead_code)]
     #[serde(rename(deserialize = "type"))]
     typ: String,
     coordinates: Vec<f64>,
@@ -20,125 +30,836 @@ pub(crate) struct GeoJSON {
 pub(crate) struct LocationIntermediate<I> {
     _id: ObjectId,
     geo_index: I,
+    // 旧数据没有该字段
+    #[serde(default = "Vec::new")]
+    geo_indices: Vec<I>,
     location: GeoJSON,
     uid: String,
+    #[serde(flatten)]
+    attributes: LocationAttributes,
+}
+
+impl<I> From<LocationIntermediate<I>> for Location<I> {
+    fn from(loc_im: LocationIntermediate<I>) -> Self {
+        Location {
+            id: loc_im._id.to_string(),
+            geo_index: loc_im.geo_index,
+            geo_indices: loc_im.geo_indices,
+            latitude: loc_im.location.coordinates[1],
+            longitude: loc_im.location.coordinates[0],
+            uid: loc_im.uid,
+            attributes: loc_im.attributes,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct LocationWithDistanceIntermediate<I> {
+    #[serde(flatten)]
+    location: LocationIntermediate<I>,
+    distance: f64,
+}
+
+// 分页跳过的条数, 页码已由校验限制了上界, 这里再防止溢出
+fn offset(page: i64, size: i64) -> i64 {
+    (page - 1).max(0).saturating_mul(size)
+}
+
+fn filter_conditions(filter: LocationFilter) -> Vec<Document> {
+    let mut conditions = Vec::new();
+    if let Some(category) = filter.category {
+        conditions.push(doc! {"category": category});
+    }
+    if !filter.tags.is_empty() {
+        match filter.tags_match {
+            TagsMatch::Any => conditions.push(doc! {"tags": {"$in": filter.tags}}),
+            TagsMatch::All => conditions.push(doc! {"tags": {"$all": filter.tags}}),
+        }
+    }
+    conditions
+}
+
+// 引入多层索引之前写入的地点没有geo_indices, 这些地点不按格子过滤, 只由距离或矩形条件匹配, 重建索引后不再命中此条件
+fn legacy_condition() -> Document {
+    doc! {"geo_indices": {"$exists": false}}
+}
+
+// geo_indices中任一索引属于cells, 或者是没有geo_indices的旧数据
+fn cells_condition(cells: Vec<Bson>) -> Document {
+    doc! {"$or": [{"geo_indices": {"$in": cells}}, legacy_condition()]}
+}
+
+// geo_indices中任一索引落在任一区间内, 只有一个值的区间合并到一个$in条件中, 没有geo_indices的旧数据同样匹配
+fn range_condition<I: Into<Bson> + PartialEq>(ranges: Vec<RangeInclusive<I>>) -> Document {
+    let (mut values, mut conditions) = (Vec::new(), Vec::new());
+    for range in ranges {
+        let (start, end) = range.into_inner();
+        if start == end {
+            values.push(start.into());
+        } else {
+            conditions.push(doc! {"geo_indices": {"$elemMatch": {"$gte": start.into(), "$lte": end.into()}}});
+        }
+    }
+    if !values.is_empty() {
+        conditions.push(doc! {"geo_indices": {"$in": values}});
+    }
+    conditions.push(legacy_condition());
+    doc! {"$or": conditions}
+}
+
+// 纬线方向每隔多少度插入一个顶点. GeoJSON多边形的边是大圆弧, 与纬线有偏差, 插入顶点后偏差在米级
+const RECT_EDGE_STEP: f64 = 0.1;
+
+// 矩形的GeoJSON多边形外环, 首尾顶点相同
+fn rect_ring(rect: &Rect) -> Vec<Vec<f64>> {
+    let steps = (rect.width() / RECT_EDGE_STEP).ceil().max(1.0) as usize;
+    let lng = |k: usize| (rect.west + rect.width() * k as f64 / steps as f64 + 540.0).rem_euclid(360.0) - 180.0;
+    let mut ring: Vec<Vec<f64>> = (0..=steps).map(|k| vec![lng(k), rect.south]).collect();
+    ring.extend((0..=steps).rev().map(|k| vec![lng(k), rect.north]));
+    ring.push(ring[0].clone());
+    ring
 }
 
 #[derive(Clone)]
 pub(crate) struct MongoPersister {
+    // 开启事务需要用到
+    client: mongodb::Client,
     db: mongodb::Database,
 }
 
+// 事务因写冲突等瞬时错误失败时的最大重试次数
+const MAX_TRANSACTION_RETRIES: u32 = 10;
+
+// 提交结果未知时在此时长内退避重试提交, 超时后返回BackendUnavailable
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(10);
+
 impl MongoPersister {
-    pub(crate) fn new(db: mongodb::Database) -> Self {
-        Self { db }
+    pub(crate) fn new(client: mongodb::Client, database: &str) -> Self {
+        Self {
+            db: client.database(database),
+            client,
+        }
+    }
+
+    fn location_document<I: Into<Bson>>(loc: LocationCommand<I>) -> Result<Document, Error> {
+        Ok(doc! {
+            "geo_index": loc.geo_index.into(),
+            "geo_indices": loc.geo_indices.into_iter().map(Into::into).collect::<Vec<Bson>>(),
+            "location": doc!{ "type": "Point", "coordinates": vec![loc.longitude, loc.latitude]},
+            "uid": loc.uid,
+            "name": loc.attributes.name,
+            "category": loc.attributes.category,
+            "tags": loc.attributes.tags,
+            "properties": to_bson(&loc.attributes.properties)?,
+        })
+    }
+
+    // 在事务中执行f, 遇到写冲突等瞬时错误时退避后重试整个事务
+    async fn with_transaction<T, F>(&self, mut f: F) -> Result<T, Error>
+    where
+        F: for<'s> FnMut(&'s mut ClientSession) -> std::pin::Pin<Box<dyn std::future::Future<Output = mongodb::error::Result<T>> + 's>>,
+    {
+        let mut session = self.client.start_session(None).await?;
+        let options = TransactionOptions::builder()
+            .read_concern(ReadConcern::snapshot())
+            .write_concern(WriteConcern::builder().w(Acknowledgment::Majority).build())
+            .build();
+        let mut attempt = 0;
+        loop {
+            session.start_transaction(options.clone()).await?;
+            let mut res = f(&mut session).await;
+            match res {
+                Ok(_) => {
+                    // 提交结果未知时可以安全地重新提交, 但主节点不可达时不能无限重试
+                    let deadline = Instant::now() + COMMIT_TIMEOUT;
+                    let mut commit_attempt = 0;
+                    loop {
+                        match timeout_at(deadline, session.commit_transaction()).await {
+                            Ok(Err(e)) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) => {
+                                if Instant::now() >= deadline {
+                                    return Err(Error::BackendUnavailable(format!("unknown transaction commit result: {e}")));
+                                }
+                                sleep(backoff(commit_attempt)).await;
+                                commit_attempt += 1;
+                            }
+                            Ok(Err(e)) => {
+                                res = Err(e);
+                                break;
+                            }
+                            Ok(Ok(_)) => break,
+                            Err(_) => return Err(Error::BackendUnavailable("timeout while committing transaction".into())),
+                        }
+                    }
+                }
+                Err(_) => {
+                    let _ = session.abort_transaction().await;
+                }
+            }
+            match res {
+                Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_RETRIES => {
+                    sleep(backoff(attempt)).await;
+                    attempt += 1;
+                }
+                res => return Ok(res?),
+            }
+        }
+    }
+
+    async fn check_fence<I: Into<Bson>>(&self, fence: Fence<I>) -> Result<(), Error> {
+        let (cells, token) = fence_cells(fence);
+        let ok = self
+            .with_transaction(|session| {
+                let (db, cells) = (self.db.clone(), cells.clone());
+                Box::pin(async move { fence_in_transaction(&db, session, cells, token).await })
+            })
+            .await?;
+        if !ok {
+            return Err(Error::LockExpired);
+        }
+        Ok(())
+    }
+}
+
+fn fence_cells<I: Into<Bson>>(fence: Fence<I>) -> (Vec<Bson>, i64) {
+    (fence.cells.into_iter().map(Into::into).collect(), fence.token as i64)
+}
+
+// 每个格子在fences集合中记录最后一次写入的token. 先读出各格子的记录, 任一记录比token大时不做任何修改并返回false,
+// 否则全部更新为token. 并发更新同一格子的事务会因写冲突而重试, 重试时就能读到对方已提交的token
+async fn fence_in_transaction(db: &mongodb::Database, session: &mut ClientSession, cells: Vec<Bson>, token: i64) -> mongodb::error::Result<bool> {
+    let collection = db.collection::<Document>("fences");
+    let newer = collection.find_one_with_session(doc! {"_id": {"$in": cells.clone()}, "token": {"$gt": token}}, None, session).await?;
+    if newer.is_some() {
+        return Ok(false);
+    }
+    for cell in cells {
+        collection
+            .update_one_with_session(doc! {"_id": cell}, doc! {"$set": {"token": token}}, UpdateOptions::builder().upsert(true).build(), session)
+            .await?;
     }
+    Ok(true)
 }
 
 impl<I> Persister<I> for MongoPersister
 where
-    for<'de> I: Into<Bson> + Deserialize<'de>,
+    for<'de> I: Into<Bson> + Deserialize<'de> + PartialEq,
 {
-    fn insert<'a>(&'a self, loc: LocationCommand<I>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<String, anyhow::Error>> + 'a>>
+    fn fence<'a>(&'a self, fence: Fence<I>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(self.check_fence(fence))
+    }
+
+    fn insert<'a>(&'a self, loc: LocationCommand<I>, fence: Fence<I>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<String, Error>> + 'a>>
     where
         I: 'a,
     {
         Box::pin(async move {
+            let (cells, token) = fence_cells(fence);
+            let document = Self::location_document(loc)?;
             let res = self
-                .db
-                .collection("locations")
-                .insert_one(
-                    doc! {
-                        "geo_index": loc.geo_index.into(),
-                        "location": doc!{ "type": "Point", "coordinates": vec![loc.longitude, loc.latitude], "uid": loc.uid}
-                    },
-                    None,
-                )
+                .with_transaction(|session| {
+                    let (db, cells, document) = (self.db.clone(), cells.clone(), document.clone());
+                    Box::pin(async move {
+                        if !fence_in_transaction(&db, session, cells, token).await? {
+                            return Ok(None);
+                        }
+                        let res = db.collection::<Document>("locations").insert_one_with_session(document, None, session).await?;
+                        Ok(res.inserted_id.as_object_id())
+                    })
+                })
                 .await?;
+            res.map(|oid| oid.to_hex()).ok_or(Error::LockExpired)
+        })
+    }
 
-            Ok(res.inserted_id.as_object_id().unwrap().to_hex())
+    fn update<'a>(&'a self, id: String, loc: LocationUpdate<I>, fence: Fence<I>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let oid = ObjectId::parse_str(&id).map_err(|_| Error::NotFound(format!("location not found: {id}")))?;
+            let (cells, token) = fence_cells(fence);
+            let update = doc! {"$set": {
+                "geo_index": loc.geo_index.into(),
+                "geo_indices": loc.geo_indices.into_iter().map(Into::into).collect::<Vec<Bson>>(),
+                "location.coordinates": vec![loc.longitude, loc.latitude],
+            }};
+            let res = self
+                .with_transaction(|session| {
+                    let (db, cells, update) = (self.db.clone(), cells.clone(), update.clone());
+                    Box::pin(async move {
+                        if !fence_in_transaction(&db, session, cells, token).await? {
+                            return Ok(None);
+                        }
+                        let res = db.collection::<Document>("locations").update_one_with_session(doc! {"_id": oid}, update, None, session).await?;
+                        Ok(Some(res.matched_count))
+                    })
+                })
+                .await?;
+            match res {
+                Some(0) => Err(Error::NotFound(format!("location not found: {id}"))),
+                Some(_) => Ok(()),
+                None => Err(Error::LockExpired),
+            }
+        })
+    }
+
+    fn get<'a>(&'a self, id: String) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<Option<Location<I>>, Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let oid = match ObjectId::parse_str(&id) {
+                Ok(oid) => oid,
+                Err(_) => return Ok(None),
+            };
+            let res = self.db.collection::<Document>("locations").find_one(doc! {"_id": oid}, None).await?;
+            match res {
+                Some(d) => {
+                    let loc_im: LocationIntermediate<I> = from_document(d)?;
+                    Ok(Some(loc_im.into()))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, id: String) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<bool, Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let oid = match ObjectId::parse_str(&id) {
+                Ok(oid) => oid,
+                Err(_) => return Ok(false),
+            };
+            let res = self.db.collection::<Document>("locations").delete_one(doc! {"_id": oid}, None).await?;
+            Ok(res.deleted_count > 0)
+        })
+    }
+
+    fn list_by_owner<'a>(&'a self, uid: String, page: i64, size: i64) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(Vec<Location<I>>, u64), Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let collection = self.db.collection::<Document>("locations");
+            let mut res = collection
+                .find(doc! {"uid": &uid}, FindOptions::builder().sort(doc! {"_id": -1}).skip(offset(page, size) as u64).limit(size).build())
+                .await?;
+            let count = collection.count_documents(doc! {"uid": &uid}, None).await?;
+            let mut l = Vec::new();
+            while let Some(v) = res.try_next().await? {
+                let loc_im: LocationIntermediate<I> = from_document(v)?;
+                l.push(loc_im.into());
+            }
+            Ok((l, count))
         })
     }
 
     fn query<'a>(
         &'a self,
-        indices: Vec<I>,
+        ranges: Vec<RangeInclusive<I>>,
         latitude: f64,
         longitude: f64,
         distance: f64,
+        filter: LocationFilter,
         page: i64,
         size: i64,
-    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(Vec<Location<I>>, u64), anyhow::Error>> + 'a>>
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(Vec<LocationWithDistance<I>>, u64), Error>> + 'a>>
     where
         I: 'a,
     {
         Box::pin(async move {
-            let condition = doc! {"$and": vec![
-                doc!{"geo_index": doc!{ "$in": indices }},
-                doc!{"location":
-                        {
-                            "$near": {
-                                "$geometry": {
-                                    "type": "Point",
-                                    "coordinates": vec![longitude, latitude]
-                                },
-                                "$maxDistance": distance
-                            }
-                        }
+            let mut conditions = vec![range_condition(ranges)];
+            conditions.extend(filter_conditions(filter));
+            let geo_near_query = doc! {"$and": conditions.clone()};
+            conditions.push(doc! {"location":
+                {
+                    "$near": {
+                        "$geometry": {
+                            "type": "Point",
+                            "coordinates": vec![longitude, latitude]
+                        },
+                        "$maxDistance": distance
                     }
-            ]};
-            let mut res: Cursor<Document> = self
-                .db
-                .collection("locations")
-                .find(condition.clone(), FindOptions::builder().limit(size).skip((page as u64 - 1) * size as u64).build())
-                .await?;
+                }
+            });
+            let condition = doc! {"$and": conditions};
+            // $near不能返回距离, 所以这里用$geoNear聚合, 由distanceField带回与查询点的距离(米)
+            let pipeline = vec![
+                doc! {"$geoNear": {
+                    "near": {
+                        "type": "Point",
+                        "coordinates": vec![longitude, latitude]
+                    },
+                    "key": "location",
+                    "distanceField": "distance",
+                    "maxDistance": distance,
+                    "query": geo_near_query,
+                    "spherical": true,
+                }},
+                doc! {"$skip": offset(page, size)},
+                doc! {"$limit": size},
+            ];
+            let mut res: Cursor<Document> = self.db.collection::<Document>("locations").aggregate(pipeline, None).await?;
             let count = self.db.run_command(doc! {"count": "locations", "query": condition}, None).await?.get_i32("n")?;
             let mut l = Vec::new();
             while let Some(v) = res.try_next().await? {
-                let loc_im: LocationIntermediate<I> = from_document(v)?;
-                let loc = Location::<I> {
-                    id: loc_im._id.to_string(),
-                    geo_index: loc_im.geo_index,
-                    latitude: loc_im.location.coordinates[1],
-                    longitude: loc_im.location.coordinates[0],
-                };
-                l.push(loc);
+                let loc_im: LocationWithDistanceIntermediate<I> = from_document(v)?;
+                l.push(LocationWithDistance {
+                    location: loc_im.location.into(),
+                    distance: loc_im.distance,
+                });
             }
             Ok((l, count as u64))
         })
     }
 
-    fn exists<'a>(&'a self, indices: Vec<I>, latitude: f64, longitude: f64, distance: f64) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<bool, anyhow::Error>> + 'a>>
+    fn query_within<'a>(
+        &'a self,
+        ranges: Vec<RangeInclusive<I>>,
+        rect: Rect,
+        filter: LocationFilter,
+        page: i64,
+        size: i64,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(Vec<Location<I>>, u64), Error>> + 'a>>
     where
         I: 'a,
     {
         Box::pin(async move {
-            let res = self
-                .db
-                .collection::<Document>("locations")
-                .find(
-                    doc! {
-                            "$and": vec![
-                                doc!{ "geo_index": doc!{ "$in": indices }},
-                                doc!{ "location": {
-                                    "$near": doc!{
-                                    "$geometry": {
-                                        "type": "Point",
-                                        "coordinates": vec![longitude, latitude],
-                                    },
-                                    "$maxDistance": distance
-                                }}}
-                            ]
-                    },
-                    None,
-                )
+            let mut conditions = vec![
+                range_condition(ranges),
+                doc! {"location": {"$geoWithin": {"$geometry": {"type": "Polygon", "coordinates": [rect_ring(&rect)]}}}},
+            ];
+            conditions.extend(filter_conditions(filter));
+            let condition = doc! {"$and": conditions};
+            let collection = self.db.collection::<Document>("locations");
+            let mut res = collection
+                .find(condition.clone(), FindOptions::builder().sort(doc! {"_id": -1}).skip(offset(page, size) as u64).limit(size).build())
                 .await?;
+            let count = collection.count_documents(condition, None).await?;
+            let mut l = Vec::new();
+            while let Some(v) = res.try_next().await? {
+                let loc_im: LocationIntermediate<I> = from_document(v)?;
+                l.push(loc_im.into());
+            }
+            Ok((l, count))
+        })
+    }
+
+    fn exists<'a>(&'a self, indices: Vec<I>, latitude: f64, longitude: f64, distance: f64, exclude: Option<String>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<bool, Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let mut conditions = vec![
+                cells_condition(indices.into_iter().map(Into::into).collect()),
+                doc! { "location": {
+                    "$near": doc!{
+                    "$geometry": {
+                        "type": "Point",
+                        "coordinates": vec![longitude, latitude],
+                    },
+                    "$maxDistance": distance
+                }}},
+            ];
+            if let Some(id) = exclude {
+                conditions.push(doc! { "_id": doc!{ "$ne": ObjectId::parse_str(&id).map_err(|e| Error::Validation(vec![FieldError { field: "id", message: e.to_string() }]))? }});
+            }
+            let res = self.db.collection::<Document>("locations").find(doc! { "$and": conditions }, None).await?;
             let count = res.count().await;
             Ok(count > 0)
         })
     }
 }
 
+// 事务中先更新周边格子的标记文档再检查重复, 并发写入相同格子的事务会因写冲突而失败重试,
+// 重试时就能看到对方已提交的地点. 事务中不能使用$near, 所以用$geoWithin按球面距离检查
+async fn exists_in_transaction(
+    db: &mongodb::Database,
+    session: &mut ClientSession,
+    cells: Vec<Bson>,
+    latitude: f64,
+    longitude: f64,
+    distance: f64,
+    exclude: Option<ObjectId>,
+) -> mongodb::error::Result<bool> {
+    for cell in &cells {
+        db.collection::<Document>("cells")
+            .update_one_with_session(doc! {"_id": cell}, doc! {"$inc": {"version": 1}}, UpdateOptions::builder().upsert(true).build(), session)
+            .await?;
+    }
+    let mut conditions = vec![
+        cells_condition(cells),
+        doc! {"location": {"$geoWithin": {"$centerSphere": [vec![longitude, latitude], distance / EARTH_RADIUS]}}},
+    ];
+    if let Some(oid) = exclude {
+        conditions.push(doc! {"_id": {"$ne": oid}});
+    }
+    let res = db.collection::<Document>("locations").find_one_with_session(doc! {"$and": conditions}, None, session).await?;
+    Ok(res.is_some())
+}
+
+impl<I> UniquePersister<I> for MongoPersister
+where
+    for<'de> I: Into<Bson> + Deserialize<'de> + PartialEq,
+{
+    fn insert_unique<'a>(&'a self, cells: Vec<I>, distance: f64, loc: LocationCommand<I>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<String, Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let cells: Vec<Bson> = cells.into_iter().map(Into::into).collect();
+            let (latitude, longitude) = (loc.latitude, loc.longitude);
+            let document = Self::location_document(loc)?;
+            let res = self
+                .with_transaction(|session| {
+                    let (db, cells, document) = (self.db.clone(), cells.clone(), document.clone());
+                    Box::pin(async move {
+                        if exists_in_transaction(&db, session, cells, latitude, longitude, distance, None).await? {
+                            return Ok(None);
+                        }
+                        let res = db.collection::<Document>("locations").insert_one_with_session(document, None, session).await?;
+                        Ok(res.inserted_id.as_object_id())
+                    })
+                })
+                .await?;
+            match res {
+                Some(oid) => Ok(oid.to_hex()),
+                None => Err(Error::Conflict("already exists location nearby".into())),
+            }
+        })
+    }
+
+    fn update_unique<'a>(&'a self, id: String, cells: Vec<I>, distance: f64, loc: LocationUpdate<I>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let oid = ObjectId::parse_str(&id).map_err(|_| Error::NotFound(format!("location not found: {id}")))?;
+            let cells: Vec<Bson> = cells.into_iter().map(Into::into).collect();
+            let (latitude, longitude) = (loc.latitude, loc.longitude);
+            let update = doc! {"$set": {
+                "geo_index": loc.geo_index.into(),
+                "geo_indices": loc.geo_indices.into_iter().map(Into::into).collect::<Vec<Bson>>(),
+                "location.coordinates": vec![longitude, latitude],
+            }};
+            let res = self
+                .with_transaction(|session| {
+                    let (db, cells, update) = (self.db.clone(), cells.clone(), update.clone());
+                    Box::pin(async move {
+                        if exists_in_transaction(&db, session, cells, latitude, longitude, distance, Some(oid)).await? {
+                            return Ok(None);
+                        }
+                        let res = db.collection::<Document>("locations").update_one_with_session(doc! {"_id": oid}, update, None, session).await?;
+                        Ok(Some(res.matched_count))
+                    })
+                })
+                .await?;
+            match res {
+                Some(0) => Err(Error::NotFound(format!("location not found: {id}"))),
+                Some(_) => Ok(()),
+                None => Err(Error::Conflict("already exists location nearby".into())),
+            }
+        })
+    }
+}
+
+impl LocationFilter {
+    fn matches(&self, attrs: &LocationAttributes) -> bool {
+        if self.category.is_some() && self.category != attrs.category {
+            return false;
+        }
+        if self.tags.is_empty() {
+            return true;
+        }
+        match self.tags_match {
+            TagsMatch::Any => self.tags.iter().any(|t| attrs.tags.contains(t)),
+            TagsMatch::All => self.tags.iter().all(|t| attrs.tags.contains(t)),
+        }
+    }
+}
+
+// 不依赖任何外部服务的实现, 用于测试和本地开发, 数据不会持久化
+#[derive(Clone)]
+pub(crate) struct InMemoryPersister<I> {
+    locations: Arc<RwLock<BTreeMap<ObjectId, Location<I>>>>,
+    // 每个格子最后一次写入的token
+    fences: Arc<RwLock<BTreeMap<I, u64>>>,
+}
+
+impl<I> InMemoryPersister<I> {
+    pub(crate) fn new() -> Self {
+        Self {
+            locations: Arc::new(RwLock::new(BTreeMap::new())),
+            fences: Arc::new(RwLock::new(BTreeMap::new())),
+        }
+    }
+
+    // 任一格子记录的token更大时整体拒绝, 不更新任何记录
+    // 由调用方持有fences的写锁, 以便在同一把锁下完成检查和写入
+    fn check_fence(fences: &mut BTreeMap<I, u64>, fence: Fence<I>) -> Result<(), Error>
+    where
+        I: Ord,
+    {
+        if fence.cells.iter().any(|cell| fences.get(cell).is_some_and(|&t| t > fence.token)) {
+            return Err(Error::LockExpired);
+        }
+        for cell in fence.cells {
+            fences.insert(cell, fence.token);
+        }
+        Ok(())
+    }
+
+    // 与$near的语义保持一致: 先按geo_indices过滤, 再按距离过滤, 结果按距离由近到远排序
+    // 由调用方持有锁, 以便在同一把写锁下完成检查和写入
+    fn nearby(
+        locations: &BTreeMap<ObjectId, Location<I>>,
+        ranges: &[RangeInclusive<I>],
+        latitude: f64,
+        longitude: f64,
+        distance: f64,
+        filter: &LocationFilter,
+        exclude: Option<&str>,
+    ) -> Vec<LocationWithDistance<I>>
+    where
+        I: Clone + Ord,
+    {
+        let mut l: Vec<LocationWithDistance<I>> = locations
+            .values()
+            .filter(|loc| in_ranges(&loc.geo_indices, ranges) && filter.matches(&loc.attributes) && Some(loc.id.as_str()) != exclude)
+            .map(|loc| LocationWithDistance {
+                location: loc.clone(),
+                distance: haversine(latitude, longitude, loc.latitude, loc.longitude),
+            })
+            .filter(|loc| loc.distance <= distance)
+            .collect();
+        l.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        l
+    }
+}
+
+// 与range_condition一致, 没有geo_indices的旧数据只按距离或矩形条件匹配
+fn in_ranges<I: Ord>(indices: &[I], ranges: &[RangeInclusive<I>]) -> bool {
+    indices.is_empty() || indices.iter().any(|i| ranges.iter().any(|r| r.contains(i)))
+}
+
+// 每个格子作为只有一个值的区间
+fn singletons<I: Clone>(cells: Vec<I>) -> Vec<RangeInclusive<I>> {
+    cells.into_iter().map(|cell| cell.clone()..=cell).collect()
+}
+
+impl<I> Persister<I> for InMemoryPersister<I>
+where
+    I: Clone + Ord,
+{
+    fn fence<'a>(&'a self, fence: Fence<I>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move { Self::check_fence(&mut self.fences.write().unwrap(), fence) })
+    }
+
+    fn insert<'a>(&'a self, loc: LocationCommand<I>, fence: Fence<I>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<String, Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let mut fences = self.fences.write().unwrap();
+            Self::check_fence(&mut fences, fence)?;
+            let oid = ObjectId::new();
+            self.locations.write().unwrap().insert(
+                oid,
+                Location {
+                    id: oid.to_hex(),
+                    latitude: loc.latitude,
+                    longitude: loc.longitude,
+                    geo_index: loc.geo_index,
+                    geo_indices: loc.geo_indices,
+                    uid: loc.uid,
+                    attributes: loc.attributes,
+                },
+            );
+            Ok(oid.to_hex())
+        })
+    }
+
+    fn update<'a>(&'a self, id: String, loc: LocationUpdate<I>, fence: Fence<I>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let oid = ObjectId::parse_str(&id).map_err(|_| Error::NotFound(format!("location not found: {id}")))?;
+            if !self.locations.read().unwrap().contains_key(&oid) {
+                return Err(Error::NotFound(format!("location not found: {id}")));
+            }
+            let mut fences = self.fences.write().unwrap();
+            Self::check_fence(&mut fences, fence)?;
+            let mut locations = self.locations.write().unwrap();
+            let old = locations.get_mut(&oid).ok_or_else(|| Error::NotFound(format!("location not found: {id}")))?;
+            old.latitude = loc.latitude;
+            old.longitude = loc.longitude;
+            old.geo_index = loc.geo_index;
+            old.geo_indices = loc.geo_indices;
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, id: String) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<Option<Location<I>>, Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let oid = match ObjectId::parse_str(&id) {
+                Ok(oid) => oid,
+                Err(_) => return Ok(None),
+            };
+            Ok(self.locations.read().unwrap().get(&oid).cloned())
+        })
+    }
+
+    fn delete<'a>(&'a self, id: String) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<bool, Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let oid = match ObjectId::parse_str(&id) {
+                Ok(oid) => oid,
+                Err(_) => return Ok(false),
+            };
+            Ok(self.locations.write().unwrap().remove(&oid).is_some())
+        })
+    }
+
+    fn list_by_owner<'a>(&'a self, uid: String, page: i64, size: i64) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(Vec<Location<I>>, u64), Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let locations = self.locations.read().unwrap();
+            let owned: Vec<&Location<I>> = locations.values().rev().filter(|loc| loc.uid == uid).collect();
+            let total = owned.len() as u64;
+            let l = owned.into_iter().skip(offset(page, size) as usize).take(size as usize).cloned().collect();
+            Ok((l, total))
+        })
+    }
+
+    fn query<'a>(
+        &'a self,
+        ranges: Vec<RangeInclusive<I>>,
+        latitude: f64,
+        longitude: f64,
+        distance: f64,
+        filter: LocationFilter,
+        page: i64,
+        size: i64,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(Vec<LocationWithDistance<I>>, u64), Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let l = Self::nearby(&self.locations.read().unwrap(), &ranges, latitude, longitude, distance, &filter, None);
+            let total = l.len() as u64;
+            Ok((l.into_iter().skip(offset(page, size) as usize).take(size as usize).collect(), total))
+        })
+    }
+
+    fn query_within<'a>(
+        &'a self,
+        ranges: Vec<RangeInclusive<I>>,
+        rect: Rect,
+        filter: LocationFilter,
+        page: i64,
+        size: i64,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(Vec<Location<I>>, u64), Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let locations = self.locations.read().unwrap();
+            let within: Vec<&Location<I>> = locations
+                .values()
+                .rev()
+                .filter(|loc| in_ranges(&loc.geo_indices, &ranges) && filter.matches(&loc.attributes) && rect.contains(loc.latitude, loc.longitude))
+                .collect();
+            let total = within.len() as u64;
+            let l = within.into_iter().skip(offset(page, size) as usize).take(size as usize).cloned().collect();
+            Ok((l, total))
+        })
+    }
+
+    fn exists<'a>(&'a self, indices: Vec<I>, latitude: f64, longitude: f64, distance: f64, exclude: Option<String>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<bool, Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            Ok(!Self::nearby(
+                &self.locations.read().unwrap(),
+                &singletons(indices),
+                latitude,
+                longitude,
+                distance,
+                &LocationFilter::default(),
+                exclude.as_deref(),
+            )
+            .is_empty())
+        })
+    }
+}
+
+impl<I> UniquePersister<I> for InMemoryPersister<I>
+where
+    I: Clone + Ord,
+{
+    fn insert_unique<'a>(&'a self, cells: Vec<I>, distance: f64, loc: LocationCommand<I>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<String, Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let mut locations = self.locations.write().unwrap();
+            if !Self::nearby(&locations, &singletons(cells), loc.latitude, loc.longitude, distance, &LocationFilter::default(), None).is_empty() {
+                return Err(Error::Conflict("already exists location nearby".into()));
+            }
+            let oid = ObjectId::new();
+            locations.insert(
+                oid,
+                Location {
+                    id: oid.to_hex(),
+                    latitude: loc.latitude,
+                    longitude: loc.longitude,
+                    geo_index: loc.geo_index,
+                    geo_indices: loc.geo_indices,
+                    uid: loc.uid,
+                    attributes: loc.attributes,
+                },
+            );
+            Ok(oid.to_hex())
+        })
+    }
+
+    fn update_unique<'a>(&'a self, id: String, cells: Vec<I>, distance: f64, loc: LocationUpdate<I>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), Error>> + 'a>>
+    where
+        I: 'a,
+    {
+        Box::pin(async move {
+            let oid = ObjectId::parse_str(&id).map_err(|_| Error::NotFound(format!("location not found: {id}")))?;
+            let mut locations = self.locations.write().unwrap();
+            if !Self::nearby(&locations, &singletons(cells), loc.latitude, loc.longitude, distance, &LocationFilter::default(), Some(&id)).is_empty() {
+                return Err(Error::Conflict("already exists location nearby".into()));
+            }
+            let old = locations.get_mut(&oid).ok_or_else(|| Error::NotFound(format!("location not found: {id}")))?;
+            old.latitude = loc.latitude;
+            old.longitude = loc.longitude;
+            old.geo_index = loc.geo_index;
+            old.geo_indices = loc.geo_indices;
+            Ok(())
+        })
+    }
+}
+
 #[cfg(test)]
 mod test {
     use mongodb::options::ClientOptions;
@@ -146,29 +867,252 @@ mod test {
     use super::*;
     #[tokio::test]
     async fn test_insert() {
-        let db = mongodb::Client::with_options(ClientOptions::parse("mongodb://localhost:27017").await.unwrap())
-            .unwrap()
-            .database("with-baby-geo");
-        let p = MongoPersister::new(db);
+        let client = mongodb::Client::with_options(ClientOptions::parse("mongodb://localhost:27017").await.unwrap()).unwrap();
+        let p = MongoPersister::new(client, "with-baby-geo");
         let res = p
-            .insert(LocationCommand {
-                latitude: 36.657004,
-                longitude: 117.0242607,
-                geo_index: 613362111795429375i64,
-                uid: "1".into(),
-            })
+            .insert(
+                LocationCommand {
+                    latitude: 36.657004,
+                    longitude: 117.0242607,
+                    geo_index: 613362111795429375i64,
+                    geo_indices: vec![613362111795429375i64],
+                    uid: "1".into(),
+                    attributes: LocationAttributes::default(),
+                },
+                Fence {
+                    token: 1,
+                    cells: vec![613362111795429375i64],
+                },
+            )
             .await
             .unwrap();
         println!("{}", res);
     }
 
+    #[test]
+    fn test_location_with_distance_from_document() {
+        let oid = ObjectId::new();
+        let d = doc! {
+            "_id": oid,
+            "geo_index": 613362111795429375i64,
+            "location": { "type": "Point", "coordinates": vec![117.0242607, 36.657004] },
+            "uid": "1",
+            "name": "nursing room",
+            "tags": vec!["free"],
+            "properties": { "floor": 2 },
+            "distance": 12.5,
+        };
+        let loc_im: LocationWithDistanceIntermediate<i64> = from_document(d).unwrap();
+        let loc = LocationWithDistance {
+            location: loc_im.location.into(),
+            distance: loc_im.distance,
+        };
+        assert_eq!(loc.location.id, oid.to_hex());
+        assert_eq!(loc.location.latitude, 36.657004);
+        assert_eq!(loc.distance, 12.5);
+        assert_eq!(loc.location.attributes.name.as_deref(), Some("nursing room"));
+        assert_eq!(loc.location.attributes.category, None);
+        assert_eq!(loc.location.attributes.tags, vec!["free".to_owned()]);
+        assert_eq!(loc.location.attributes.properties, Some(serde_json::json!({ "floor": 2 })));
+    }
+
+    #[test]
+    fn test_filter_conditions() {
+        assert!(filter_conditions(LocationFilter::default()).is_empty());
+        let conditions = filter_conditions(LocationFilter {
+            category: Some("nursing-room".into()),
+            tags: vec!["free".into(), "indoor".into()],
+            tags_match: TagsMatch::All,
+        });
+        assert_eq!(conditions, vec![doc! {"category": "nursing-room"}, doc! {"tags": {"$all": ["free", "indoor"]}}]);
+    }
+
+    #[test]
+    fn test_range_condition() {
+        assert_eq!(
+            range_condition(vec![1i64..=1, 4..=7, 2..=2]),
+            doc! {"$or": [
+                {"geo_indices": {"$elemMatch": {"$gte": 4i64, "$lte": 7i64}}},
+                {"geo_indices": {"$in": [1i64, 2i64]}},
+                {"geo_indices": {"$exists": false}},
+            ]}
+        );
+    }
+
+    fn command(latitude: f64, longitude: f64, uid: &str, category: Option<&str>) -> LocationCommand<i64> {
+        LocationCommand {
+            latitude,
+            longitude,
+            geo_index: 1,
+            geo_indices: vec![1],
+            uid: uid.into(),
+            attributes: LocationAttributes {
+                category: category.map(str::to_owned),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn fence(token: u64) -> Fence<i64> {
+        Fence { token, cells: vec![1] }
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_query() {
+        let p = InMemoryPersister::new();
+        let far = p.insert(command(36.667004, 117.0242607, "1", None), fence(1)).await.unwrap();
+        let near = p.insert(command(36.658004, 117.0242607, "1", Some("nursing-room")), fence(1)).await.unwrap();
+        p.insert(command(37.657004, 117.0242607, "2", None), fence(1)).await.unwrap();
+        let (l, total) = p.query(vec![1..=1], 36.657004, 117.0242607, 2000.0, LocationFilter::default(), 1, 10).await.unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(l.iter().map(|l| l.location.id.clone()).collect::<Vec<_>>(), vec![near.clone(), far.clone()]);
+        assert!(l[0].distance < l[1].distance);
+        let (l, total) = p.query(vec![1..=1], 36.657004, 117.0242607, 2000.0, LocationFilter::default(), 2, 1).await.unwrap();
+        assert_eq!((l.len(), total), (1, 2));
+        assert_eq!(l[0].location.id, far);
+        let filter = LocationFilter {
+            category: Some("nursing-room".into()),
+            ..Default::default()
+        };
+        let (l, total) = p.query(vec![1..=1], 36.657004, 117.0242607, 2000.0, filter, 1, 10).await.unwrap();
+        assert_eq!((l.len(), total), (1, 1));
+        let (l, _) = p.query(vec![2..=2], 36.657004, 117.0242607, 2000.0, LocationFilter::default(), 1, 10).await.unwrap();
+        assert!(l.is_empty());
+        // 区间内的任一索引都能匹配
+        let (_, total) = p.query(vec![2..=3, 0..=1], 36.657004, 117.0242607, 2000.0, LocationFilter::default(), 1, 10).await.unwrap();
+        assert_eq!(total, 2);
+    }
+
+    #[test]
+    fn test_rect_ring() {
+        let ring = rect_ring(&Rect {
+            south: -1.0,
+            west: 179.75,
+            north: 1.0,
+            east: -179.75,
+        });
+        // 南北边各6个顶点, 再加上闭合的顶点
+        assert_eq!(ring.len(), 13);
+        assert_eq!(ring.first(), ring.last());
+        assert_eq!(ring[0], vec![179.75, -1.0]);
+        assert_eq!(ring[5], vec![-179.75, -1.0]);
+        assert_eq!(ring[6], vec![-179.75, 1.0]);
+        assert!(ring.iter().all(|c| (-180.0..=180.0).contains(&c[0])));
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_query_within() {
+        let p = InMemoryPersister::new();
+        let first = p.insert(command(36.657004, 117.0242607, "1", None), fence(1)).await.unwrap();
+        let second = p.insert(command(36.667004, 117.0242607, "1", Some("nursing-room")), fence(1)).await.unwrap();
+        p.insert(command(36.757004, 117.0242607, "2", None), fence(1)).await.unwrap();
+        let rect = Rect {
+            south: 36.6,
+            west: 117.0,
+            north: 36.7,
+            east: 117.1,
+        };
+        // 由新到旧排序
+        let (l, total) = p.query_within(vec![1..=1], rect, LocationFilter::default(), 1, 10).await.unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(l.iter().map(|l| l.id.clone()).collect::<Vec<_>>(), vec![second.clone(), first]);
+        let filter = LocationFilter {
+            category: Some("nursing-room".into()),
+            ..Default::default()
+        };
+        let (l, total) = p.query_within(vec![1..=1], rect, filter, 1, 10).await.unwrap();
+        assert_eq!((l.len(), total), (1, 1));
+        assert_eq!(l[0].id, second);
+        let (l, total) = p.query_within(vec![1..=1], rect, LocationFilter::default(), 2, 1).await.unwrap();
+        assert_eq!((l.len(), total), (1, 2));
+        let (_, total) = p.query_within(vec![2..=2], rect, LocationFilter::default(), 1, 10).await.unwrap();
+        assert_eq!(total, 0);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_exists() {
+        let p = InMemoryPersister::new();
+        let id = p.insert(command(36.657004, 117.0242607, "1", None), fence(1)).await.unwrap();
+        assert!(p.exists(vec![1], 36.658004, 117.0242607, 500.0, None).await.unwrap());
+        assert!(!p.exists(vec![1], 36.658004, 117.0242607, 100.0, None).await.unwrap());
+        assert!(!p.exists(vec![1], 36.658004, 117.0242607, 500.0, Some(id.clone())).await.unwrap());
+        assert!(p.delete(id.clone()).await.unwrap());
+        assert!(p.get(id).await.unwrap().is_none());
+        assert!(!p.exists(vec![1], 36.658004, 117.0242607, 500.0, None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_legacy_location() {
+        let p = InMemoryPersister::new();
+        // 没有geo_indices的旧数据只按距离或矩形条件匹配
+        let id = p
+            .insert(
+                LocationCommand {
+                    geo_indices: Vec::new(),
+                    ..command(36.657004, 117.0242607, "1", None)
+                },
+                fence(1),
+            )
+            .await
+            .unwrap();
+        let (l, total) = p.query(vec![2..=2], 36.658004, 117.0242607, 500.0, LocationFilter::default(), 1, 10).await.unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(l[0].location.id, id);
+        let (_, total) = p.query(vec![2..=2], 36.658004, 117.0242607, 100.0, LocationFilter::default(), 1, 10).await.unwrap();
+        assert_eq!(total, 0);
+        let rect = Rect {
+            south: 36.6,
+            west: 117.0,
+            north: 36.7,
+            east: 117.1,
+        };
+        let (_, total) = p.query_within(vec![2..=2], rect, LocationFilter::default(), 1, 10).await.unwrap();
+        assert_eq!(total, 1);
+        assert!(p.exists(vec![2], 36.658004, 117.0242607, 500.0, None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_fence() {
+        let p = InMemoryPersister::new();
+        p.insert(command(36.657004, 117.0242607, "1", None), fence(2)).await.unwrap();
+        // 锁过期后才写入的持有者token更小, 整体拒绝, 不会更新其他格子的记录
+        match p.insert(command(36.667004, 117.0242607, "1", None), Fence { token: 1, cells: vec![2, 1] }).await {
+            Err(Error::LockExpired) => {}
+            _ => panic!("expected lock expired"),
+        }
+        assert_eq!(p.locations.read().unwrap().len(), 1);
+        assert!(p.fences.read().unwrap().get(&2).is_none());
+        let id = p.insert(command(36.667004, 117.0242607, "1", None), Fence { token: 3, cells: vec![2, 1] }).await.unwrap();
+        let update = LocationUpdate {
+            latitude: 36.668004,
+            longitude: 117.0242607,
+            geo_index: 2,
+            geo_indices: vec![2],
+        };
+        match p.update(id, update, Fence { token: 2, cells: vec![2] }).await {
+            Err(Error::LockExpired) => {}
+            _ => panic!("expected lock expired"),
+        }
+        // 后来的持有者在重复检查之前记录了token, 之后锁已过期的持有者即使已经通过了重复检查, 写入也会被拒绝
+        p.fence(Fence { token: 5, cells: vec![3] }).await.unwrap();
+        match p.insert(command(36.757004, 117.0242607, "1", None), Fence { token: 4, cells: vec![3] }).await {
+            Err(Error::LockExpired) => {}
+            _ => panic!("expected lock expired"),
+        }
+        assert_eq!(p.locations.read().unwrap().len(), 2);
+        match p.fence(Fence { token: 4, cells: vec![4, 3] }).await {
+            Err(Error::LockExpired) => {}
+            _ => panic!("expected lock expired"),
+        }
+        assert!(p.fences.read().unwrap().get(&4).is_none());
+    }
+
     #[tokio::test]
     async fn test_exists() {
         let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await.unwrap();
         client_options.app_name = Some("with-baby-geo".to_owned());
-        let db = mongodb::Client::with_options(client_options).unwrap().database("with_baby_geo");
-        let p = MongoPersister::new(db);
-        let res = p.exists(vec![613362111795429375i64], 36.65, 117.02, 100000.0).await.unwrap();
+        let p = MongoPersister::new(mongodb::Client::with_options(client_options).unwrap(), "with_baby_geo");
+        let res = p.exists(vec![613362111795429375i64], 36.65, 117.02, 100000.0, None).await.unwrap();
         println!("{}", res);
     }
 
@@ -183,7 +1127,7 @@ mod test {
                     "count": "locations",
                     "query": doc!{
                             "$and": vec![
-                                doc!{ "geo_index": doc!{ "$in": vec![613362111795429375i64] }},
+                                doc!{ "geo_indices": doc!{ "$in": vec![613362111795429375i64] }},
                                 doc!{ "location": {
                                     "$near": doc!{
                                     "$geometry": {
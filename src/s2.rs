@@ -0,0 +1,259 @@
+// S2格子的计算, 只实现了索引需要的部分: 经纬度与格子id的转换, 同层相邻格子, 格子与球冠的距离
+// 算法和常量与 https://github.com/google/s2geometry 保持一致, 生成的格子id可以与其他S2实现互通
+use crate::geo::{boundary_distance, normalize, Point};
+use std::collections::{BTreeSet, VecDeque};
+
+pub(crate) const MAX_LEVEL: u8 = 30;
+const MAX_SIZE: i64 = 1 << MAX_LEVEL;
+const POS_BITS: u32 = 2 * MAX_LEVEL as u32 + 1;
+
+// 希尔伯特曲线的方向: 是否交换i和j, 是否取反
+const SWAP_MASK: usize = 1;
+const INVERT_MASK: usize = 2;
+const IJ_TO_POS: [[u64; 4]; 4] = [[0, 1, 3, 2], [0, 3, 1, 2], [2, 3, 1, 0], [2, 1, 3, 0]];
+const POS_TO_IJ: [[i64; 4]; 4] = [[0, 1, 3, 2], [0, 2, 3, 1], [3, 2, 0, 1], [3, 1, 0, 2]];
+const POS_TO_ORIENTATION: [usize; 4] = [SWAP_MASK, 0, 0, INVERT_MASK | SWAP_MASK];
+
+fn face_uv_to_xyz(face: usize, u: f64, v: f64) -> Point {
+    match face {
+        0 => [1.0, u, v],
+        1 => [-u, 1.0, v],
+        2 => [-u, -v, 1.0],
+        3 => [-1.0, -v, -u],
+        4 => [v, -1.0, -u],
+        _ => [v, u, -1.0],
+    }
+}
+
+fn xyz_to_face_uv(p: &Point) -> (usize, f64, f64) {
+    let mut face = (0..3).max_by(|&a, &b| p[a].abs().total_cmp(&p[b].abs())).unwrap();
+    if p[face] < 0.0 {
+        face += 3;
+    }
+    let (u, v) = match face {
+        0 => (p[1] / p[0], p[2] / p[0]),
+        1 => (-p[0] / p[1], p[2] / p[1]),
+        2 => (-p[0] / p[2], -p[1] / p[2]),
+        3 => (p[2] / p[0], p[1] / p[0]),
+        4 => (p[2] / p[1], -p[0] / p[1]),
+        _ => (-p[1] / p[2], -p[0] / p[2]),
+    };
+    (face, u, v)
+}
+
+// 二次变换, 使各个格子的面积更均匀
+fn st_to_uv(s: f64) -> f64 {
+    if s >= 0.5 {
+        (4.0 * s * s - 1.0) / 3.0
+    } else {
+        (1.0 - 4.0 * (1.0 - s) * (1.0 - s)) / 3.0
+    }
+}
+
+fn uv_to_st(u: f64) -> f64 {
+    if u >= 0.0 {
+        0.5 * (1.0 + 3.0 * u).sqrt()
+    } else {
+        1.0 - 0.5 * (1.0 - 3.0 * u).sqrt()
+    }
+}
+
+fn st_to_ij(s: f64) -> i64 {
+    ((MAX_SIZE as f64 * s).floor() as i64).clamp(0, MAX_SIZE - 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct CellId(pub u64);
+
+impl CellId {
+    pub(crate) fn from_point(p: &Point) -> Self {
+        let (face, u, v) = xyz_to_face_uv(p);
+        Self::from_face_ij(face, st_to_ij(uv_to_st(u)), st_to_ij(uv_to_st(v)))
+    }
+
+    fn from_face_ij(face: usize, i: i64, j: i64) -> Self {
+        let mut orientation = face & SWAP_MASK;
+        let mut pos = 0u64;
+        for k in (0..MAX_LEVEL).rev() {
+            let ij = (((i >> k) & 1) << 1 | ((j >> k) & 1)) as usize;
+            let p = IJ_TO_POS[orientation][ij];
+            pos = pos << 2 | p;
+            orientation ^= POS_TO_ORIENTATION[p as usize];
+        }
+        Self((face as u64) << POS_BITS | pos << 1 | 1)
+    }
+
+    // i或j超出当前面时, 投影到相邻的面上
+    fn from_face_ij_wrap(face: usize, i: i64, j: i64) -> Self {
+        let (i, j) = (i.clamp(-1, MAX_SIZE), j.clamp(-1, MAX_SIZE));
+        let scale = 1.0 / MAX_SIZE as f64;
+        let limit = 1.0 + f64::EPSILON;
+        let u = (scale * (2 * i + 1 - MAX_SIZE) as f64).clamp(-limit, limit);
+        let v = (scale * (2 * j + 1 - MAX_SIZE) as f64).clamp(-limit, limit);
+        let (face, u, v) = xyz_to_face_uv(&face_uv_to_xyz(face, u, v));
+        Self::from_face_ij(face, st_to_ij(0.5 * (u + 1.0)), st_to_ij(0.5 * (v + 1.0)))
+    }
+
+    pub(crate) fn face(&self) -> usize {
+        (self.0 >> POS_BITS) as usize
+    }
+
+    fn lsb(&self) -> u64 {
+        self.0 & self.0.wrapping_neg()
+    }
+
+    pub(crate) fn level(&self) -> u8 {
+        MAX_LEVEL - (self.0.trailing_zeros() / 2) as u8
+    }
+
+    pub(crate) fn parent(&self, level: u8) -> Self {
+        let lsb = 1u64 << (2 * (MAX_LEVEL - level) as u32);
+        Self((self.0 & lsb.wrapping_neg()) | lsb)
+    }
+
+    // 在level层的后代中id最小和最大的格子, level不能比当前层粗
+    pub(crate) fn child_range(&self, level: u8) -> (CellId, CellId) {
+        let (lsb, child_lsb) = (self.lsb(), 1u64 << (2 * (MAX_LEVEL - level) as u32));
+        (Self(self.0 - lsb + child_lsb), Self(self.0 + lsb - child_lsb))
+    }
+
+    pub(crate) fn contains(&self, other: &CellId) -> bool {
+        let lsb = self.lsb();
+        self.0 - (lsb - 1) <= other.0 && other.0 <= self.0 + (lsb - 1)
+    }
+
+    // 格子左下角的i, j以及格子的边长
+    fn face_ij(&self) -> (usize, i64, i64, i64) {
+        let face = self.face();
+        let level = self.level();
+        let mut orientation = face & SWAP_MASK;
+        let (mut i, mut j) = (0, 0);
+        for k in 0..level {
+            let pos = ((self.0 >> (POS_BITS - 2 - 2 * k as u32)) & 3) as usize;
+            let ij = POS_TO_IJ[orientation][pos];
+            i = i << 1 | ij >> 1;
+            j = j << 1 | ij & 1;
+            orientation ^= POS_TO_ORIENTATION[pos];
+        }
+        let shift = MAX_LEVEL - level;
+        (face, i << shift, j << shift, 1 << shift)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn center(&self) -> Point {
+        let (face, i, j, size) = self.face_ij();
+        let s = (i as f64 + size as f64 / 2.0) / MAX_SIZE as f64;
+        let t = (j as f64 + size as f64 / 2.0) / MAX_SIZE as f64;
+        normalize(face_uv_to_xyz(face, st_to_uv(s), st_to_uv(t)))
+    }
+
+    // 逆时针顺序的四个顶点
+    pub(crate) fn vertices(&self) -> [Point; 4] {
+        let (face, i, j, size) = self.face_ij();
+        let uv = |i: i64| st_to_uv(i as f64 / MAX_SIZE as f64);
+        let (u0, u1, v0, v1) = (uv(i), uv(i + size), uv(j), uv(j + size));
+        [
+            normalize(face_uv_to_xyz(face, u0, v0)),
+            normalize(face_uv_to_xyz(face, u1, v0)),
+            normalize(face_uv_to_xyz(face, u1, v1)),
+            normalize(face_uv_to_xyz(face, u0, v1)),
+        ]
+    }
+
+    // 同层中共享一条边的四个格子
+    pub(crate) fn edge_neighbors(&self) -> [CellId; 4] {
+        let level = self.level();
+        let (face, i, j, size) = self.face_ij();
+        [
+            Self::from_face_ij_wrap(face, i, j - size).parent(level),
+            Self::from_face_ij_wrap(face, i + size, j).parent(level),
+            Self::from_face_ij_wrap(face, i, j + size).parent(level),
+            Self::from_face_ij_wrap(face, i - size, j).parent(level),
+        ]
+    }
+
+    // 点到格子的最短球面距离(弧度), 格子的边都是大圆弧
+    pub(crate) fn distance(&self, p: &Point) -> f64 {
+        if self.contains(&CellId::from_point(p)) {
+            return 0.0;
+        }
+        boundary_distance(p, &self.vertices())
+    }
+}
+
+// 与以center为中心、radius(弧度)为半径的球冠相交的所有level层格子
+pub(crate) fn cover_cap(center: &Point, radius: f64, level: u8) -> Vec<CellId> {
+    let start = CellId::from_point(center).parent(level);
+    let mut seen = BTreeSet::from([start]);
+    let mut queue = VecDeque::from([start]);
+    let mut res = Vec::new();
+    // 球冠是凸的, 与其相交的格子通过边相连, 所以从中心所在的格子开始广度优先搜索即可
+    while let Some(cell) = queue.pop_front() {
+        res.push(cell);
+        for n in cell.edge_neighbors() {
+            if seen.insert(n) && n.distance(center) <= radius {
+                queue.push_back(n);
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::geo::lat_lng_to_point;
+
+    #[test]
+    fn test_cell_id() {
+        // 经纬度(0, 0)位于0号面的中心
+        let id = CellId::from_point(&lat_lng_to_point(0.0, 0.0));
+        assert_eq!(id.0, 0x1000000000000001);
+        assert_eq!(id.level(), MAX_LEVEL);
+        assert_eq!(id.parent(0).0, 0x1000000000000000);
+        assert_eq!(CellId::from_point(&lat_lng_to_point(90.0, 0.0)).face(), 2);
+        assert_eq!(CellId::from_point(&lat_lng_to_point(0.0, 180.0)).face(), 3);
+        let p = lat_lng_to_point(36.657004, 117.0242607);
+        let leaf = CellId::from_point(&p);
+        for level in 0..=MAX_LEVEL {
+            let cell = leaf.parent(level);
+            assert_eq!(cell.level(), level);
+            assert!(cell.contains(&leaf));
+            assert_eq!(cell.distance(&p), 0.0);
+            let (first, last) = cell.child_range(MAX_LEVEL);
+            assert!(first <= leaf && leaf <= last);
+            assert_eq!((first.parent(level), last.parent(level)), (cell, cell));
+            // 中心点落在格子内, 由顶点和中心解码出的格子与原格子一致
+            assert_eq!(CellId::from_point(&cell.center()).parent(level), cell);
+        }
+    }
+
+    #[test]
+    fn test_edge_neighbors() {
+        // 0号面与除对面(3号面)之外的所有面相邻
+        let face = CellId(0x1000000000000000);
+        let mut faces: Vec<usize> = face.edge_neighbors().iter().map(CellId::face).collect();
+        faces.sort();
+        assert_eq!(faces, vec![1, 2, 4, 5]);
+        // 相邻关系是对称的, 包括跨面的格子
+        for (lat, lng) in [(36.657004, 117.0242607), (45.0, 45.0), (35.26, -135.0), (-89.9, 10.0)] {
+            let cell = CellId::from_point(&lat_lng_to_point(lat, lng)).parent(10);
+            for n in cell.edge_neighbors() {
+                assert_eq!(n.level(), 10);
+                assert_ne!(n, cell);
+                assert!(n.edge_neighbors().contains(&cell), "{:x} is not neighbor of {:x}", cell.0, n.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cover_cap() {
+        let center = lat_lng_to_point(36.657004, 117.0242607);
+        let cover = cover_cap(&center, 0.0, 13);
+        assert_eq!(cover, vec![CellId::from_point(&center).parent(13)]);
+        // 半径越大覆盖的格子越多, 且都与球冠相交
+        let cover = cover_cap(&center, 2000.0 / 6378100.0, 13);
+        assert!(cover.len() > 1);
+        assert!(cover.iter().all(|c| c.distance(&center) <= 2000.0 / 6378100.0));
+    }
+}
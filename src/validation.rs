@@ -0,0 +1,121 @@
+use crate::error::Error;
+use crate::models::LocationAttributes;
+use serde::Serialize;
+
+pub(crate) const MAX_PAGE_SIZE: i64 = 100;
+pub(crate) const MAX_PAGE: i64 = 10000;
+pub(crate) const MAX_NAME_LEN: usize = 100;
+pub(crate) const MAX_CATEGORY_LEN: usize = 32;
+pub(crate) const MAX_TAG_LEN: usize = 32;
+pub(crate) const MAX_TAGS: usize = 20;
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+pub(crate) trait Validate {
+    fn validate(&self) -> Result<(), Error>;
+}
+
+// 收集所有不合法的字段, 一次性返回给客户端
+#[derive(Default)]
+pub(crate) struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn check(&mut self, field: &'static str, ok: bool, message: impl Into<String>) -> &mut Self {
+        if !ok {
+            self.errors.push(FieldError { field, message: message.into() });
+        }
+        self
+    }
+
+    pub(crate) fn latitude(&mut self, field: &'static str, v: f64) -> &mut Self {
+        self.check(field, v.is_finite() && (-90.0..=90.0).contains(&v), "must be a finite number between -90 and 90")
+    }
+
+    pub(crate) fn longitude(&mut self, field: &'static str, v: f64) -> &mut Self {
+        self.check(field, v.is_finite() && (-180.0..=180.0).contains(&v), "must be a finite number between -180 and 180")
+    }
+
+    pub(crate) fn page(&mut self, field: &'static str, v: i64) -> &mut Self {
+        self.check(field, (1..=MAX_PAGE).contains(&v), format!("must be between 1 and {MAX_PAGE}"))
+    }
+
+    pub(crate) fn size(&mut self, field: &'static str, v: i64) -> &mut Self {
+        self.check(field, (1..=MAX_PAGE_SIZE).contains(&v), format!("must be between 1 and {MAX_PAGE_SIZE}"))
+    }
+
+    pub(crate) fn attributes(&mut self, attrs: &LocationAttributes) -> &mut Self {
+        if let Some(name) = &attrs.name {
+            self.check(
+                "name",
+                !name.trim().is_empty() && name.chars().count() <= MAX_NAME_LEN,
+                format!("must be 1 to {MAX_NAME_LEN} characters"),
+            );
+        }
+        if let Some(category) = &attrs.category {
+            self.check(
+                "category",
+                !category.trim().is_empty() && category.chars().count() <= MAX_CATEGORY_LEN,
+                format!("must be 1 to {MAX_CATEGORY_LEN} characters"),
+            );
+        }
+        self.check("tags", attrs.tags.len() <= MAX_TAGS, format!("must contain at most {MAX_TAGS} tags"));
+        self.check(
+            "tags",
+            attrs.tags.iter().all(|t| !t.trim().is_empty() && t.chars().count() <= MAX_TAG_LEN),
+            format!("each tag must be 1 to {MAX_TAG_LEN} characters"),
+        );
+        if let Some(properties) = &attrs.properties {
+            self.check("properties", properties.is_object(), "must be a JSON object");
+        }
+        self
+    }
+
+    pub(crate) fn finish(&mut self) -> Result<(), Error> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        Err(Error::Validation(std::mem::take(&mut self.errors)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validator() {
+        assert!(Validator::new()
+            .latitude("latitude", 36.657004)
+            .longitude("longitude", 117.0242607)
+            .page("page", 1)
+            .size("size", 10)
+            .finish()
+            .is_ok());
+        let err = Validator::new()
+            .latitude("latitude", 500.0)
+            .longitude("longitude", f64::NAN)
+            .page("page", 0)
+            .size("size", -5)
+            .finish()
+            .unwrap_err();
+        match err {
+            Error::Validation(fields) => {
+                let names: Vec<&str> = fields.iter().map(|f| f.field).collect();
+                assert_eq!(names, vec!["latitude", "longitude", "page", "size"]);
+            }
+            e => panic!("unexpected error: {e}"),
+        }
+        assert!(Validator::new().page("page", MAX_PAGE).finish().is_ok());
+        assert!(Validator::new().page("page", i64::MAX).finish().is_err());
+    }
+}